@@ -1,38 +1,98 @@
 use anyhow::{bail, Context, Result};
-use serde::{Deserialize, Serialize};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io::{self, BufRead, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileEntry {
     hash: String,
     synced_at: chrono::DateTime<chrono::Utc>,
+    /// First file of the hardlink group this file belongs to, if any
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_opt_path",
+        deserialize_with = "deserialize_opt_path"
+    )]
+    hardlink: Option<PathBuf>,
+    /// Host that pushed this ref file, used to name its branches if they diverge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    /// Hosts that removed this file from a `GIT_ADDITIVE_DIRS` directory, e.g.
+    /// with `git gc`. Pulls on those hosts don't bring it back.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pruned_by: Vec<String>,
+    /// Hosts that have had this file from a `GIT_ADDITIVE_DIRS` directory.
+    /// Only they can prune it; a host that never had it still needs it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    held_by: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Manifests before version 1 stored lossily converted, unescaped paths.
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
 struct Manifest {
-    files: HashMap<String, FileEntry>,
+    #[serde(default)]
+    version: u32,
+    /// When the project was last pushed, and from which host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pushed_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    #[serde(serialize_with = "serialize_path_map", deserialize_with = "deserialize_path_map")]
+    files: HashMap<PathBuf, FileEntry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            pushed_at: None,
+            host: None,
+            files: HashMap::new(),
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+    let args_os: Vec<OsString> = std::env::args_os().collect();
 
-    if args.len() < 2 {
+    if args_os.len() < 2 {
         print_usage();
         return Ok(());
     }
 
+    // File names need not be UTF-8; every other argument must be
+    match args_os[1].to_str() {
+        Some("add") => return cmd_add(&args_os),
+        Some("remove") => return cmd_remove(&args_os),
+        _ => {}
+    }
+    let args = args_os
+        .into_iter()
+        .map(|arg| {
+            arg.into_string()
+                .map_err(|arg| anyhow::anyhow!("Argument is not valid UTF-8: {}", arg.to_string_lossy()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
     match args[1].as_str() {
         "init" => cmd_init(&args)?,
-        "push" => cmd_push()?,
-        "pull" => cmd_pull()?,
-        "status" => cmd_status()?,
-        "add" => cmd_add(&args)?,
-        "remove" => cmd_remove(&args)?,
+        "clone" => cmd_clone(&args)?,
+        "push" => cmd_push(&args)?,
+        "pull" => cmd_pull(&args)?,
+        "status" => cmd_status(&args)?,
+        "list-remote" => cmd_list_remote(&args)?,
+        "migrate-config" => cmd_migrate_config()?,
+        "target" => cmd_target(&args)?,
         "--help" | "-h" | "help" => print_usage(),
         other => {
             eprintln!("Unknown command: {}", other);
@@ -48,35 +108,96 @@ fn print_usage() {
     eprintln!("Usage: local-sync <command>");
     eprintln!();
     eprintln!("Commands:");
-    eprintln!("  init <path>     Initialize with NAS target path");
+    eprintln!("  init [path]     Initialize with NAS target path, derived from the user");
+    eprintln!("                  config's nas_root and template if omitted");
+    eprintln!("  clone <path> [dir]");
+    eprintln!("                  Create a new checkout of a project on the NAS");
     eprintln!("  push            Copy local files to NAS");
     eprintln!("  pull            Copy NAS files to local");
     eprintln!("  status          Show sync status");
-    eprintln!("  add <file>      Add a gitignored file to sync");
-    eprintln!("  remove <file>   Remove a file from additional sync list");
+    eprintln!("  list-remote <root>");
+    eprintln!("                  List projects stored under a NAS directory");
+    eprintln!("  target add <name> <path> [--default]");
+    eprintln!("                  Add a named sync target");
+    eprintln!("  target remove <name>");
+    eprintln!("                  Remove a sync target (its NAS files are kept)");
+    eprintln!("  target default <name>");
+    eprintln!("                  Set the target used when --target is not given");
+    eprintln!("  target list     List configured targets");
+    eprintln!("  add <file>...   Add gitignored files, directories or glob patterns to sync");
+    eprintln!("  remove <file>...");
+    eprintln!("                  Remove files or patterns from additional sync list");
+    eprintln!("  migrate-config  Convert a legacy .local-sync file to .local-sync.toml");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --target <name> Sync with a named target instead of the default (push, pull, status)");
+    eprintln!("  --force         Sync even while a git operation is in progress (push, pull)");
 }
 
 fn cmd_init(args: &[String]) -> Result<()> {
-    if args.len() < 3 {
-        bail!("Usage: local-sync init <path>");
+    let project_root = std::env::current_dir()?;
+    let config_path = project_root.join(CONFIG_FILE);
+
+    let nas_path = match args.get(2) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let nas_path = derive_target_path(&load_user_config()?, &project_root)?;
+            println!("Derived NAS path from user config: {}", nas_path.display());
+            nas_path
+        }
+    };
+
+    if project_root.join(LEGACY_CONFIG_FILE).exists() {
+        bail!(
+            "{} already exists in {}\nRun 'local-sync migrate-config' to convert it.",
+            LEGACY_CONFIG_FILE,
+            project_root.display()
+        );
     }
 
-    let nas_path = PathBuf::from(&args[2]);
-    let project_root = std::env::current_dir()?;
-    let config_path = project_root.join(".local-sync");
+    // The committable config is shared with whoever already created it; the
+    // target itself stays private to this checkout when there is a git directory.
+    let shared_exists = config_path.exists();
+    let mut config_file = if shared_exists {
+        load_config_file(&project_root)?
+    } else {
+        ConfigFile::default()
+    };
+    let local = has_git_dir(&project_root);
+    let mut local_file = load_local_config_file(&project_root)?;
+    let (target_config, target_path) = if local {
+        (&mut local_file.target_config, local_config_path(&project_root))
+    } else {
+        (&mut config_file.target_config, config_path.clone())
+    };
 
-    if config_path.exists() {
+    if target_config.targets.contains_key(DEFAULT_TARGET) {
         bail!(
-            ".local-sync already exists at {}\nRemove it first if you want to reinitialize.",
-            config_path.display()
+            "{} already configures a target\nRemove it first if you want to reinitialize.",
+            target_path.display()
         );
     }
+    target_config.targets.insert(
+        DEFAULT_TARGET.to_string(),
+        TargetSection {
+            path: nas_path.display().to_string(),
+            hosts: BTreeMap::new(),
+        },
+    );
+    target_config.default_target = Some(DEFAULT_TARGET.to_string());
 
-    fs::write(&config_path, format!("{}\n", nas_path.display()))
-        .with_context(|| format!("Failed to write {}", config_path.display()))?;
+    if !shared_exists || !local {
+        save_config_file(&project_root, &config_file)?;
+    }
+    if local {
+        save_local_config_file(&project_root, &local_file)?;
+    }
 
     println!("Initialized local-sync with NAS path: {}", nas_path.display());
     println!("Config written to: {}", config_path.display());
+    if local {
+        println!("Target written to: {} (not committed)", target_path.display());
+    }
 
     // Check if NAS already has this project
     let manifest_path = nas_path.join(".local-sync-manifest");
@@ -88,141 +209,487 @@ fn cmd_init(args: &[String]) -> Result<()> {
     Ok(())
 }
 
-fn cmd_add(args: &[String]) -> Result<()> {
-    if args.len() < 3 {
-        bail!("Usage: local-sync add <file|directory>");
+fn cmd_clone(args: &[String]) -> Result<()> {
+    let Some(nas_arg) = args.get(2) else {
+        bail!("Usage: local-sync clone <path> [dir]");
+    };
+
+    let nas_path = std::path::absolute(nas_arg)
+        .with_context(|| format!("Invalid NAS path: {}", nas_arg))?;
+    if !nas_path.join(".local-sync-manifest").exists() {
+        bail!("No local-sync project found at {}", nas_path.display());
     }
 
-    let file_path = &args[2];
-    let git_root = find_git_root()?;
-    let config_path = git_root.join(".local-sync");
+    let dir = match args.get(3) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            nas_path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Cannot derive a directory name from {}", nas_path.display()))?,
+        ),
+    };
+    if dir.exists() && dir.read_dir()?.next().is_some() {
+        bail!("Destination already exists and is not empty: {}", dir.display());
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let root = dir.canonicalize()?;
 
-    if !config_path.exists() {
-        bail!("Not initialized. Run 'local-sync init <path>' first.");
+    println!("Cloning {} into {}", nas_path.display(), root.display());
+    let config = Config {
+        git_root: root.clone(),
+        git_dir: root.join(".git"),
+        common_dir: root.join(".git"),
+        host: host_name(),
+        target_name: DEFAULT_TARGET.to_string(),
+        nas_path: nas_path.clone(),
+        additional_files: Vec::new(),
+        exclude_patterns: Vec::new(),
+        git_include: Vec::new(),
+        lfs: LfsMode::Sync,
+        export_work: false,
+        preserve_hardlinks: true,
+        // The shared config is not here yet; a mirror on the NAS tells us the mode
+        git_transport: if nas_path.join(GIT_MIRROR_DIR).is_dir() {
+            GitTransport::Mirror
+        } else {
+            GitTransport::Files
+        },
+        // Clone runs its own check
+        verify_pull: VerifyMode::Off,
+        // No branch is checked out yet
+        extras_prefix: None,
+    };
+    pull(&config)?;
+
+    // The shared config usually arrives with the pull; the target is ours to write
+    let has_shared = root.join(CONFIG_FILE).exists() || root.join(LEGACY_CONFIG_FILE).exists();
+    let target = TargetSection {
+        path: nas_path.display().to_string(),
+        hosts: BTreeMap::new(),
+    };
+    if has_git_dir(&root) {
+        let mut local_file = load_local_config_file(&root)?;
+        local_file.target_config.targets.insert(DEFAULT_TARGET.to_string(), target);
+        local_file.target_config.default_target = Some(DEFAULT_TARGET.to_string());
+        save_local_config_file(&root, &local_file)?;
+        if !has_shared {
+            save_config_file(&root, &ConfigFile::default())?;
+        }
+    } else {
+        let mut config_file = if has_shared {
+            load_editable_config_file(&root)?
+        } else {
+            ConfigFile::default()
+        };
+        config_file.target_config.targets.insert(DEFAULT_TARGET.to_string(), target);
+        config_file.target_config.default_target = Some(DEFAULT_TARGET.to_string());
+        save_config_file(&root, &config_file)?;
     }
 
-    // Check if file/directory exists
-    let full_path = git_root.join(file_path);
-    if !full_path.exists() {
-        bail!("Path does not exist: {}", file_path);
+    // Branch-scoped additional files can only be found once a branch is checked out
+    let config = load_config_from_root(root.clone(), None)?;
+    if config.extras_prefix.is_some() {
+        pull(&config)?;
+    } else if config.export_work && has_git_dir(&root) {
+        import_work(&config)?;
     }
 
-    // Check if already tracked by git (for files, not directories)
-    if full_path.is_file() {
-        let git_files = get_git_files(&git_root)?;
-        if git_files.contains(&file_path.to_string()) {
-            bail!("File is already tracked by git: {}", file_path);
+    if has_git_dir(&root) {
+        println!("Verifying repository...");
+        let status = Command::new("git")
+            .current_dir(&root)
+            .args(["fsck", "--no-progress"])
+            .status()
+            .context("Failed to run git fsck")?;
+        if !status.success() {
+            bail!("git fsck reported problems in {}; the clone may be incomplete", root.display());
         }
     }
 
-    // Read current config
-    let content = fs::read_to_string(&config_path)?;
-    let lines: Vec<&str> = content.lines().collect();
+    println!("Cloned into {}", root.display());
+    Ok(())
+}
+
+fn cmd_add(args: &[OsString]) -> Result<()> {
+    if args.len() < 3 {
+        bail!("Usage: local-sync add <file|directory|pattern>...");
+    }
+
+    let git_root = find_git_root()?;
+    let mut config_file = load_editable_config_file(&git_root)?;
+
+    // Reported only once every argument has been accepted and saved
+    let mut added = Vec::new();
+    for arg in &args[2..] {
+        let entry = include_entry(arg);
+        let file_path = Path::new(arg);
 
-    // Check if already added
-    for line in &lines[1..] {
-        if line.trim() == format!("+{}", file_path) {
-            bail!("Path already in sync list: {}", file_path);
+        // Check if already added
+        if config_file.include.paths.contains(&entry) {
+            bail!("Path already in sync list: {}", file_path.display());
         }
-    }
 
-    // Append to config
-    let mut new_content = content.trim_end().to_string();
-    new_content.push_str(&format!("\n+{}\n", file_path));
-    fs::write(&config_path, new_content)?;
+        // Patterns are stored as-is and matched against gitignored files on every sync
+        if is_pattern(&entry) {
+            let includes = build_includes(&git_root, std::slice::from_ref(&entry))?;
+            let matches = get_ignored_files(&git_root)?
+                .iter()
+                .filter(|rel_path| is_matched(&includes, rel_path))
+                .count();
+            added.push(format!("Added pattern to sync: {} (currently matches {} files)", entry, matches));
+            config_file.include.paths.push(entry);
+            continue;
+        }
+
+        // Check if file/directory exists
+        let full_path = safe_join(&git_root, file_path)?;
+        if !full_path.exists() {
+            bail!("Path does not exist: {}", file_path.display());
+        }
+
+        // Check if already tracked by git (for files, not directories)
+        if full_path.is_file() {
+            let git_files = get_git_files(&git_root)?;
+            if git_files.contains(&file_path.to_path_buf()) {
+                bail!("File is already tracked by git: {}", file_path.display());
+            }
+        }
+
+        let path_type = if full_path.is_dir() { "directory" } else { "file" };
+        added.push(format!("Added {} to sync: {}", path_type, file_path.display()));
+        config_file.include.paths.push(entry);
+    }
 
-    let path_type = if full_path.is_dir() { "directory" } else { "file" };
-    println!("Added {} to sync: {}", path_type, file_path);
+    save_config_file(&git_root, &config_file)?;
+    for message in added {
+        println!("{}", message);
+    }
     Ok(())
 }
 
-fn cmd_remove(args: &[String]) -> Result<()> {
+fn cmd_remove(args: &[OsString]) -> Result<()> {
     if args.len() < 3 {
-        bail!("Usage: local-sync remove <file>");
+        bail!("Usage: local-sync remove <file|pattern>...");
     }
 
-    let file_path = &args[2];
     let git_root = find_git_root()?;
-    let config_path = git_root.join(".local-sync");
+    let mut config_file = load_editable_config_file(&git_root)?;
+    let git_files = get_git_files(&git_root)?;
 
-    if !config_path.exists() {
-        bail!("Not initialized. Run 'local-sync init <path>' first.");
+    let mut removed = Vec::new();
+    for arg in &args[2..] {
+        let entry = include_entry(arg);
+        let file_path = Path::new(arg);
+
+        // Check if tracked by git (can't remove git-tracked files)
+        if git_files.contains(&file_path.to_path_buf()) {
+            bail!("Cannot remove git-tracked file from sync: {}", file_path.display());
+        }
+
+        let Some(index) = config_file.include.paths.iter().position(|path| *path == entry) else {
+            bail!("File not in additional sync list: {}", file_path.display());
+        };
+
+        config_file.include.paths.remove(index);
+        removed.push(file_path);
     }
 
-    // Check if tracked by git (can't remove git-tracked files)
-    let git_files = get_git_files(&git_root)?;
-    if git_files.contains(&file_path.to_string()) {
-        bail!("Cannot remove git-tracked file from sync: {}", file_path);
+    save_config_file(&git_root, &config_file)?;
+    for file_path in removed {
+        println!("Removed from sync: {}", file_path.display());
     }
+    Ok(())
+}
 
-    // Read current config
-    let content = fs::read_to_string(&config_path)?;
-    let lines: Vec<&str> = content.lines().collect();
+/// The form of a file argument in `include.paths`: patterns as given, names
+/// encoded like manifest paths so that they need not be UTF-8.
+fn include_entry(arg: &OsStr) -> String {
+    match arg.to_str() {
+        Some(pattern) if is_pattern(pattern) => pattern.to_string(),
+        _ => encode_path(Path::new(arg)),
+    }
+}
 
-    let target = format!("+{}", file_path);
-    let mut found = false;
-    let mut new_lines: Vec<&str> = Vec::new();
+fn cmd_migrate_config() -> Result<()> {
+    let root = find_project_root()?;
+    let legacy_path = root.join(LEGACY_CONFIG_FILE);
 
-    for line in &lines {
-        if line.trim() == target {
-            found = true;
-        } else {
-            new_lines.push(line);
-        }
+    if root.join(CONFIG_FILE).exists() {
+        bail!("{} already exists in {}", CONFIG_FILE, root.display());
+    }
+
+    let content = fs::read_to_string(&legacy_path)
+        .with_context(|| format!("Failed to read {}", legacy_path.display()))?;
+    let mut config_file = parse_legacy_config(&content)?;
+    let target = config_file.target_config.targets[DEFAULT_TARGET].path.clone();
+
+    // Keep the machine-specific NAS path out of the committable file
+    if has_git_dir(&root) {
+        let mut local_file = load_local_config_file(&root)?;
+        let target_config = std::mem::take(&mut config_file.target_config);
+        local_file.target_config.targets.extend(target_config.targets);
+        local_file
+            .target_config
+            .default_target
+            .get_or_insert_with(|| DEFAULT_TARGET.to_string());
+        save_local_config_file(&root, &local_file)?;
     }
 
-    if !found {
-        bail!("File not in additional sync list: {}", file_path);
+    save_config_file(&root, &config_file)?;
+    fs::remove_file(&legacy_path)
+        .with_context(|| format!("Failed to remove {}", legacy_path.display()))?;
+
+    println!("Migrated {} to {}", LEGACY_CONFIG_FILE, CONFIG_FILE);
+    println!("  Target: {}", target);
+    println!("  Additional paths: {}", config_file.include.paths.len());
+    Ok(())
+}
+
+fn cmd_target(args: &[String]) -> Result<()> {
+    let usage = "Usage: local-sync target add <name> <path> [--default] | remove <name> | default <name> | list";
+    let Some(action) = args.get(2) else {
+        bail!(usage);
+    };
+
+    let root = find_project_root()?;
+    let local = has_git_dir(&root);
+    let mut config_file = if local {
+        load_config_file(&root)?
+    } else {
+        load_editable_config_file(&root)?
+    };
+    let mut local_file = load_local_config_file(&root)?;
+
+    if action == "list" {
+        let local_names: HashSet<_> = local_file.target_config.targets.keys().cloned().collect();
+        let merged = merge_target_configs(config_file.target_config, local_file.target_config);
+        for (name, target) in &merged.targets {
+            let marker = if merged.default_target.as_deref() == Some(name.as_str()) {
+                "*"
+            } else {
+                " "
+            };
+            let source = if local_names.contains(name) { "local" } else { "shared" };
+            println!("{} {:<12} {} ({})", marker, name, target.path, source);
+            for (host, path) in &target.hosts {
+                println!("    on {:<9} {}", host, path);
+            }
+        }
+        return Ok(());
     }
 
-    let new_content = new_lines.join("\n") + "\n";
-    fs::write(&config_path, new_content)?;
+    let shared_names: HashSet<_> = config_file.target_config.targets.keys().cloned().collect();
+    let has_default = config_file.target_config.default_target.is_some()
+        || local_file.target_config.default_target.is_some();
+
+    // Targets are machine-specific, so they go into the private file when there is one
+    let target_config = if local {
+        &mut local_file.target_config
+    } else {
+        &mut config_file.target_config
+    };
+
+    match (action.as_str(), &args[3..]) {
+        ("add", [name, path, rest @ ..]) => {
+            if target_config.targets.contains_key(name) {
+                bail!("Target already exists: {}", name);
+            }
+            target_config.targets.insert(
+                name.clone(),
+                TargetSection {
+                    path: path.clone(),
+                    hosts: BTreeMap::new(),
+                },
+            );
+            if rest.iter().any(|arg| arg == "--default") || !has_default {
+                target_config.default_target = Some(name.clone());
+            }
+            println!("Added target {}: {}", name, path);
+        }
+        ("remove", [name]) => {
+            if target_config.targets.remove(name).is_none() {
+                if shared_names.contains(name) {
+                    bail!("Target {} is defined in {}; remove it there", name, CONFIG_FILE);
+                }
+                bail!("No such target: {}", name);
+            }
+            if target_config.default_target.as_ref() == Some(name) {
+                target_config.default_target = None;
+            }
+            println!("Removed target: {}", name);
+        }
+        ("default", [name]) => {
+            if !target_config.targets.contains_key(name) && !shared_names.contains(name) {
+                bail!("No such target: {}", name);
+            }
+            target_config.default_target = Some(name.clone());
+            println!("Default target: {}", name);
+        }
+        _ => bail!(usage),
+    }
 
-    println!("Removed from sync: {}", file_path);
+    if local {
+        save_local_config_file(&root, &local_file)?;
+    } else {
+        save_config_file(&root, &config_file)?;
+    }
     Ok(())
 }
 
-fn cmd_push() -> Result<()> {
-    let config = get_config()?;
-    let sync_files = get_sync_files(&config)?;
+fn cmd_push(args: &[String]) -> Result<()> {
+    let config = get_config(option_value(args, "--target")?.as_deref())?;
+    check_git_idle(&config, args.iter().any(|arg| arg == "--force"))?;
+    let SyncFiles {
+        files: sync_files,
+        hardlinks,
+    } = get_sync_files(&config)?;
     let manifest = load_manifest(&config.nas_path)?;
+    check_extras_branch(&config, &manifest)?;
+
+    // Refuse to silently overwrite files whose names the NAS considers equal
+    let collisions = find_path_collisions(&sync_files, manifest.files.keys());
+    if !collisions.is_empty() {
+        eprintln!("Path collisions detected (names that are identical on a case-insensitive or Unicode-normalizing NAS):");
+        for group in &collisions {
+            let paths: Vec<_> = group.iter().map(|path| path.display().to_string()).collect();
+            eprintln!("  {}", paths.join("  <->  "));
+        }
+        eprintln!();
+        if !prompt_continue("Do you want to continue? Colliding files will overwrite each other on NAS.")? {
+            eprintln!("Aborted.");
+            return Ok(());
+        }
+    }
 
     // Ensure NAS directory exists
     fs::create_dir_all(&config.nas_path)
         .with_context(|| format!("Failed to create NAS directory: {}", config.nas_path.display()))?;
 
+    if config.git_transport == GitTransport::Mirror {
+        push_git_mirror(&config)?;
+    }
+
     let mut new_manifest = Manifest::default();
     let mut conflicts = Vec::new();
     let mut to_copy = Vec::new();
+    let mut to_merge = Vec::new();
     let mut to_delete = Vec::new();
 
     // Check each file to sync
     for rel_path in &sync_files {
-        let local_path = config.git_root.join(rel_path);
-        let nas_file_path = config.nas_path.join(rel_path);
+        let local_path = local_file_path(&config, rel_path)?;
+        let nas_file_path = safe_join(&config.nas_path, rel_path)?;
 
         if !local_path.exists() {
             continue;
         }
 
+        // Object files never change once written, so an existing copy is current
+        if is_immutable_object(rel_path) && nas_file_path.exists() {
+            let entry = match manifest.files.get(rel_path) {
+                Some(entry) => entry.clone(),
+                None => FileEntry {
+                    hash: hash_file(&nas_file_path)?,
+                    synced_at: chrono::Utc::now(),
+                    hardlink: None,
+                    host: None,
+                    pruned_by: Vec::new(),
+                    held_by: Vec::new(),
+                },
+            };
+            new_manifest.files.insert(rel_path.clone(), entry);
+            continue;
+        }
+
         let local_hash = hash_file(&local_path)?;
+        let hardlink = hardlinks.get(rel_path).cloned();
 
-        // Check for conflicts
-        if let Some(manifest_entry) = manifest.files.get(rel_path) {
-            if nas_file_path.exists() {
-                let nas_hash = hash_file(&nas_file_path)?;
-                // Conflict: both changed since last sync
-                if local_hash != manifest_entry.hash && nas_hash != manifest_entry.hash {
-                    conflicts.push(rel_path.clone());
-                    continue;
+        // Branch refs are resolved by ancestry rather than by content
+        let ref_update = compare_refs(
+            &config,
+            rel_path,
+            &local_path,
+            &nas_file_path,
+            &config.nas_path.join(PACKED_REFS),
+        )?;
+        match ref_update {
+            Some(RefUpdate::Behind) => {
+                println!("Kept: {} (NAS is ahead, pull to fast-forward)", rel_path.display());
+                keep_nas_ref(&manifest, &mut new_manifest, rel_path, &nas_file_path)?;
+                continue;
+            }
+            Some(RefUpdate::Diverged) => {
+                let kept_rel = kept_ref_path(rel_path, &config.host)?;
+                let kept_nas = safe_join(&config.nas_path, &kept_rel)?;
+                println!(
+                    "Diverged: {} (NAS branch kept, local one stored as {})",
+                    rel_path.display(),
+                    kept_rel.display()
+                );
+                to_copy.push((kept_rel.clone(), local_path.clone(), kept_nas));
+                new_manifest.files.insert(
+                    kept_rel,
+                    FileEntry {
+                        hash: local_hash,
+                        synced_at: chrono::Utc::now(),
+                        hardlink: None,
+                        host: None,
+                        pruned_by: Vec::new(),
+                        held_by: Vec::new(),
+                    },
+                );
+                keep_nas_ref(&manifest, &mut new_manifest, rel_path, &nas_file_path)?;
+                continue;
+            }
+            _ => {}
+        }
+
+        // packed-refs is merged ref by ref rather than overwritten
+        if rel_path == Path::new(PACKED_REFS) && nas_file_path.exists() {
+            let nas_hash = hash_file(&nas_file_path)?;
+            if nas_hash != local_hash {
+                let merged = merge_packed_refs(&config, &local_path, &nas_file_path, &config.host)?;
+                let merged_hash = hash_bytes(merged.as_bytes());
+                if merged_hash != nas_hash {
+                    to_merge.push((rel_path.clone(), nas_file_path.clone(), merged));
                 }
+                new_manifest.files.insert(
+                    rel_path.clone(),
+                    FileEntry {
+                        hash: merged_hash,
+                        synced_at: chrono::Utc::now(),
+                        hardlink: None,
+                        host: Some(config.host.clone()),
+                        pruned_by: Vec::new(),
+                        held_by: Vec::new(),
+                    },
+                );
+                continue;
+            }
+        }
+
+        // Check for conflicts
+        if ref_update.is_none()
+            && let Some(manifest_entry) = manifest.files.get(rel_path)
+            && nas_file_path.exists()
+        {
+            let nas_hash = hash_file(&nas_file_path)?;
+            // Conflict: both changed since last sync
+            if local_hash != manifest_entry.hash && nas_hash != manifest_entry.hash {
+                conflicts.push(rel_path.clone());
+                continue;
             }
         }
 
         // Check if copy needed
         let needs_copy = if nas_file_path.exists() {
             let nas_hash = hash_file(&nas_file_path)?;
+            let leader = hardlink
+                .as_ref()
+                .map(|leader| safe_join(&config.nas_path, leader))
+                .transpose()?;
             local_hash != nas_hash
+                || leader.is_some_and(|leader| needs_relink(&leader, &nas_file_path))
         } else {
             true
         };
@@ -236,16 +703,33 @@ fn cmd_push() -> Result<()> {
             FileEntry {
                 hash: local_hash,
                 synced_at: chrono::Utc::now(),
+                hardlink,
+                host: is_ref_file(rel_path).then(|| config.host.clone()),
+                pruned_by: Vec::new(),
+                held_by: Vec::new(),
             },
         );
     }
 
     // Find deleted files (in manifest but not in sync files)
     let sync_files_set: HashSet<_> = sync_files.iter().cloned().collect();
-    for (rel_path, _) in &manifest.files {
-        if !sync_files_set.contains(rel_path) {
-            let nas_file_path = config.nas_path.join(rel_path);
-            if nas_file_path.exists() {
+    let sync_keys: HashMap<_, _> = sync_files.iter().map(|path| (collision_key(path), path)).collect();
+    for (rel_path, manifest_entry) in &manifest.files {
+        if !sync_files_set.contains(rel_path) && !new_manifest.files.contains_key(rel_path) {
+            let nas_file_path = safe_join(&config.nas_path, rel_path)?;
+            if !nas_file_path.exists() {
+                continue;
+            }
+            // After a case-only or normalization rename, a NAS that sees both
+            // names as equal holds the renamed file under the old one
+            if let Some(renamed) = sync_keys.get(&collision_key(rel_path))
+                && is_same_file(&nas_file_path, &safe_join(&config.nas_path, renamed)?)
+            {
+                continue;
+            }
+            if is_git_additive(rel_path) || is_other_branch_extra(&config, rel_path) {
+                new_manifest.files.insert(rel_path.clone(), manifest_entry.clone());
+            } else {
                 to_delete.push((rel_path.clone(), nas_file_path));
             }
         }
@@ -255,7 +739,7 @@ fn cmd_push() -> Result<()> {
     if !conflicts.is_empty() {
         eprintln!("Conflicts detected (modified both locally and on NAS):");
         for path in &conflicts {
-            eprintln!("  {}", path);
+            eprintln!("  {}", path.display());
         }
         eprintln!();
         if !prompt_continue("Do you want to continue? Local changes will overwrite NAS.")? {
@@ -265,50 +749,80 @@ fn cmd_push() -> Result<()> {
 
         // Re-add conflicts to copy list
         for rel_path in conflicts {
-            let local_path = config.git_root.join(&rel_path);
-            let nas_file_path = config.nas_path.join(&rel_path);
+            let local_path = local_file_path(&config, &rel_path)?;
+            let nas_file_path = safe_join(&config.nas_path, &rel_path)?;
             let local_hash = hash_file(&local_path)?;
 
+            let hardlink = hardlinks.get(&rel_path).cloned();
+            let host = is_ref_file(&rel_path).then(|| config.host.clone());
+
             to_copy.push((rel_path.clone(), local_path, nas_file_path));
             new_manifest.files.insert(
                 rel_path,
                 FileEntry {
                     hash: local_hash,
                     synced_at: chrono::Utc::now(),
+                    hardlink,
+                    host,
+                    pruned_by: Vec::new(),
+                    held_by: Vec::new(),
                 },
             );
         }
     }
 
-    // Perform copies
-    for (rel_path, local_path, nas_file_path) in &to_copy {
-        if let Some(parent) = nas_file_path.parent() {
-            fs::create_dir_all(parent)?;
+    // Perform copies, leaving hardlinked files until their group's first file is in place
+    let (linked, regular): (Vec<_>, Vec<_>) = to_copy
+        .iter()
+        .partition(|(rel_path, _, _)| hardlinks.contains_key(rel_path));
+    for (rel_path, local_path, nas_file_path) in regular {
+        copy_file(local_path, nas_file_path)
+            .with_context(|| format!("Failed to copy {}", rel_path.display()))?;
+        println!("Copied: {}", rel_path.display());
+    }
+    for (rel_path, local_path, nas_file_path) in linked {
+        let leader = safe_join(&config.nas_path, &hardlinks[rel_path])?;
+        if link_or_copy(&leader, local_path, nas_file_path)
+            .with_context(|| format!("Failed to copy {}", rel_path.display()))?
+        {
+            println!("Linked: {}", rel_path.display());
+        } else {
+            println!("Copied: {}", rel_path.display());
         }
-        fs::copy(local_path, nas_file_path)
-            .with_context(|| format!("Failed to copy {}", rel_path))?;
-        println!("Copied: {}", rel_path);
+    }
+    for (rel_path, nas_file_path, merged) in &to_merge {
+        fs::write(nas_file_path, merged)
+            .with_context(|| format!("Failed to write {}", rel_path.display()))?;
+        println!("Merged: {}", rel_path.display());
     }
 
     // Perform deletions
     for (rel_path, nas_file_path) in &to_delete {
         fs::remove_file(nas_file_path)
-            .with_context(|| format!("Failed to delete {}", rel_path))?;
-        println!("Deleted: {}", rel_path);
+            .with_context(|| format!("Failed to delete {}", rel_path.display()))?;
+        println!("Deleted: {}", rel_path.display());
         // Clean up empty parent directories
         cleanup_empty_dirs(&config.nas_path, nas_file_path)?;
     }
 
     // Save manifest
+    update_holders(&config, &mut new_manifest)?;
+    new_manifest.pushed_at = Some(chrono::Utc::now());
+    new_manifest.host = Some(config.host.clone());
     save_manifest(&config.nas_path, &new_manifest)?;
+    record_extras_branch(&config)?;
 
-    let total_changes = to_copy.len() + to_delete.len();
+    if config.export_work && has_git_dir(&config.git_root) {
+        export_work(&config)?;
+    }
+
+    let total_changes = to_copy.len() + to_merge.len() + to_delete.len();
     if total_changes == 0 {
         println!("Already up to date.");
     } else {
         println!(
             "Push complete: {} copied, {} deleted",
-            to_copy.len(),
+            to_copy.len() + to_merge.len(),
             to_delete.len()
         );
     }
@@ -316,8 +830,13 @@ fn cmd_push() -> Result<()> {
     Ok(())
 }
 
-fn cmd_pull() -> Result<()> {
-    let config = get_config_for_pull()?;
+fn cmd_pull(args: &[String]) -> Result<()> {
+    let config = get_config_for_pull(option_value(args, "--target")?.as_deref())?;
+    check_git_idle(&config, args.iter().any(|arg| arg == "--force"))?;
+    pull(&config)
+}
+
+fn pull(config: &Config) -> Result<()> {
     let manifest = load_manifest(&config.nas_path)?;
 
     if manifest.files.is_empty() && !config.nas_path.exists() {
@@ -325,28 +844,151 @@ fn cmd_pull() -> Result<()> {
         return Ok(());
     }
 
+    // The index is not synced, so it has to follow HEAD wherever the pull
+    // moves it, in submodules too
+    let heads_before = checkout_heads(&config.git_root);
+    let tips_before = ref_tips(&config.git_root);
+
+    if config.git_transport == GitTransport::Mirror {
+        pull_git_mirror(config)?;
+    }
+
+    let hardlinks: HashMap<PathBuf, PathBuf> = manifest
+        .files
+        .iter()
+        .filter_map(|(rel_path, entry)| Some((rel_path.clone(), entry.hardlink.clone()?)))
+        .collect();
+
     let mut new_manifest = Manifest::default();
     let mut conflicts = Vec::new();
     let mut to_copy = Vec::new();
+    let mut to_merge = Vec::new();
     let mut to_delete = Vec::new();
+    let mut pruned = Vec::new();
+
+    // Volatile git files pushed by older versions are left alone on both sides
+    let git_policy = build_git_policy(config)?;
+
+    // A fresh checkout takes every object, even ones this host pruned elsewhere
+    let fresh = !config.git_dir.exists();
+    let repacked = if fresh {
+        HashSet::new()
+    } else {
+        find_repacked_objects(config, &manifest)?
+    };
 
     // Check each file in manifest
     for (rel_path, manifest_entry) in &manifest.files {
-        let local_path = config.git_root.join(rel_path);
-        let nas_file_path = config.nas_path.join(rel_path);
+        if is_git_skipped(&git_policy, rel_path) {
+            continue;
+        }
+
+        // Other branches' additional files stay on the NAS for when they are checked out
+        if is_other_branch_extra(config, rel_path) {
+            new_manifest.files.insert(rel_path.clone(), manifest_entry.clone());
+            continue;
+        }
+
+        let local_path = local_file_path(config, rel_path)?;
+        let nas_file_path = safe_join(&config.nas_path, rel_path)?;
 
         if !nas_file_path.exists() {
-            // File deleted on NAS, delete locally if it exists
-            if local_path.exists() {
+            // File deleted on NAS, delete locally if it exists. A branch ref that
+            // moved locally survives, e.g. when the other side packed its refs.
+            if local_path.exists()
+                && !is_git_additive(rel_path)
+                && !(is_branch_ref(rel_path) && hash_file(&local_path)? != manifest_entry.hash)
+            {
                 to_delete.push((rel_path.clone(), local_path.clone()));
             }
             continue;
         }
 
+        // Files this host removed from an additive directory stay removed
+        if !fresh && manifest_entry.pruned_by.contains(&config.host) && !local_path.exists() {
+            new_manifest.files.insert(rel_path.clone(), manifest_entry.clone());
+            pruned.push((rel_path.clone(), nas_file_path, local_path));
+            continue;
+        }
+
+        // Object files never change once written, so an existing copy is current.
+        // The same goes for objects that a gc here moved into another pack.
+        if is_immutable_object(rel_path) && (local_path.exists() || repacked.contains(rel_path)) {
+            new_manifest.files.insert(rel_path.clone(), manifest_entry.clone());
+            continue;
+        }
+
         let nas_hash = hash_file(&nas_file_path)?;
+        let pushed_by = manifest_entry
+            .host
+            .as_deref()
+            .or(manifest.host.as_deref())
+            .unwrap_or("nas");
+
+        // Branch refs are resolved by ancestry rather than by content
+        let ref_update = compare_refs(
+            config,
+            rel_path,
+            &nas_file_path,
+            &local_path,
+            &local_file_path(config, Path::new(PACKED_REFS))?,
+        )?;
+        match ref_update {
+            Some(RefUpdate::Behind) => {
+                println!("Kept: {} (local branch is ahead)", rel_path.display());
+            }
+            Some(RefUpdate::Diverged) => {
+                let kept_rel = kept_ref_path(rel_path, pushed_by)?;
+                let kept_local = local_file_path(config, &kept_rel)?;
+                println!(
+                    "Diverged: {} (local branch kept, NAS one stored as {})",
+                    rel_path.display(),
+                    kept_rel.display()
+                );
+                to_copy.push((kept_rel, nas_file_path.clone(), kept_local));
+            }
+            _ => {}
+        }
+        if matches!(ref_update, Some(RefUpdate::Behind | RefUpdate::Diverged)) {
+            new_manifest.files.insert(
+                rel_path.clone(),
+                FileEntry {
+                    hash: nas_hash,
+                    synced_at: chrono::Utc::now(),
+                    hardlink: None,
+                    host: manifest_entry.host.clone(),
+                    pruned_by: manifest_entry.pruned_by.clone(),
+                    held_by: manifest_entry.held_by.clone(),
+                },
+            );
+            continue;
+        }
+
+        // packed-refs is merged ref by ref rather than overwritten
+        if rel_path == Path::new(PACKED_REFS) && local_path.exists() {
+            let local_hash = hash_file(&local_path)?;
+            if local_hash != nas_hash {
+                let merged = merge_packed_refs(config, &nas_file_path, &local_path, pushed_by)?;
+                if hash_bytes(merged.as_bytes()) != local_hash {
+                    to_merge.push((rel_path.clone(), local_path.clone(), merged));
+                }
+            }
+            new_manifest.files.insert(
+                rel_path.clone(),
+                FileEntry {
+                    hash: nas_hash,
+                    synced_at: chrono::Utc::now(),
+                    hardlink: None,
+                    host: manifest_entry.host.clone(),
+                    pruned_by: manifest_entry.pruned_by.clone(),
+                    held_by: manifest_entry.held_by.clone(),
+                },
+            );
+            continue;
+        }
 
         // Check for conflicts
-        if local_path.exists() {
+        if ref_update.is_none() && local_path.exists() {
             let local_hash = hash_file(&local_path)?;
             // Conflict: both changed since last sync
             if local_hash != manifest_entry.hash && nas_hash != manifest_entry.hash {
@@ -356,6 +998,10 @@ fn cmd_pull() -> Result<()> {
                     FileEntry {
                         hash: nas_hash,
                         synced_at: chrono::Utc::now(),
+                        hardlink: manifest_entry.hardlink.clone(),
+                        host: manifest_entry.host.clone(),
+                        pruned_by: manifest_entry.pruned_by.clone(),
+                        held_by: manifest_entry.held_by.clone(),
                     },
                 );
                 continue;
@@ -365,7 +1011,13 @@ fn cmd_pull() -> Result<()> {
         // Check if copy needed
         let needs_copy = if local_path.exists() {
             let local_hash = hash_file(&local_path)?;
+            let leader = manifest_entry
+                .hardlink
+                .as_ref()
+                .map(|leader| local_file_path(config, leader))
+                .transpose()?;
             local_hash != nas_hash
+                || leader.is_some_and(|leader| needs_relink(&leader, &local_path))
         } else {
             true
         };
@@ -379,27 +1031,39 @@ fn cmd_pull() -> Result<()> {
             FileEntry {
                 hash: nas_hash,
                 synced_at: chrono::Utc::now(),
+                hardlink: manifest_entry.hardlink.clone(),
+                host: manifest_entry.host.clone(),
+                pruned_by: manifest_entry.pruned_by.clone(),
+                held_by: manifest_entry.held_by.clone(),
             },
         );
     }
 
     // Also check for new files on NAS that aren't in manifest
-    // (could happen if manifest was lost or this is first pull)
+    // (could happen if manifest was lost or this is first pull). A NAS that
+    // normalizes names may list a manifest file under a different spelling.
+    let manifest_keys: HashSet<_> = manifest.files.keys().map(|path| collision_key(path)).collect();
+    let excludes = build_excludes(config)?;
     if config.nas_path.exists() {
         for entry in walkdir(&config.nas_path)? {
-            let rel_path = entry
-                .strip_prefix(&config.nas_path)
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
+            let rel_path = entry.strip_prefix(&config.nas_path).unwrap().to_path_buf();
 
-            if rel_path == ".local-sync-manifest" {
+            if rel_path == Path::new(".local-sync-manifest")
+                || rel_path.starts_with(GIT_MIRROR_DIR)
+                || rel_path.starts_with(WORK_DIR)
+                || is_other_branch_extra(config, &rel_path)
+                || is_matched(&excludes, local_rel(config, &rel_path))
+                || is_git_skipped(&git_policy, &rel_path)
+                || skip_special_file(&entry)
+            {
                 continue;
             }
 
-            if !manifest.files.contains_key(&rel_path) {
-                let local_path = config.git_root.join(&rel_path);
-                let nas_file_path = config.nas_path.join(&rel_path);
+            if !manifest.files.contains_key(&rel_path)
+                && !manifest_keys.contains(&collision_key(&rel_path))
+            {
+                let local_path = local_file_path(config, &rel_path)?;
+                let nas_file_path = safe_join(&config.nas_path, &rel_path)?;
 
                 if !local_path.exists() {
                     to_copy.push((rel_path.clone(), nas_file_path.clone(), local_path));
@@ -409,6 +1073,10 @@ fn cmd_pull() -> Result<()> {
                         FileEntry {
                             hash: nas_hash,
                             synced_at: chrono::Utc::now(),
+                            hardlink: None,
+                            host: None,
+                            pruned_by: Vec::new(),
+                            held_by: Vec::new(),
                         },
                     );
                 }
@@ -420,7 +1088,7 @@ fn cmd_pull() -> Result<()> {
     if !conflicts.is_empty() {
         eprintln!("Conflicts detected (modified both locally and on NAS):");
         for path in &conflicts {
-            eprintln!("  {}", path);
+            eprintln!("  {}", path.display());
         }
         eprintln!();
         if !prompt_continue("Do you want to continue? NAS changes will overwrite local.")? {
@@ -430,214 +1098,2428 @@ fn cmd_pull() -> Result<()> {
 
         // Re-add conflicts to copy list
         for rel_path in conflicts {
-            let local_path = config.git_root.join(&rel_path);
-            let nas_file_path = config.nas_path.join(&rel_path);
+            let local_path = local_file_path(config, &rel_path)?;
+            let nas_file_path = safe_join(&config.nas_path, &rel_path)?;
             to_copy.push((rel_path, nas_file_path, local_path));
         }
     }
 
-    // Perform copies
-    for (rel_path, nas_file_path, local_path) in &to_copy {
-        if let Some(parent) = local_path.parent() {
-            fs::create_dir_all(parent)?;
+    // Keep copies of the files about to change so a failed check can undo them
+    let backup = if config.verify_pull == VerifyMode::Rollback {
+        let changed = to_copy
+            .iter()
+            .map(|(_, _, local_path)| local_path)
+            .chain(to_merge.iter().map(|(_, local_path, _)| local_path))
+            .chain(to_delete.iter().map(|(_, local_path)| local_path));
+        Some(backup_files(changed)?)
+    } else {
+        None
+    };
+
+    // Perform copies, leaving hardlinked files until their group's first file is in place
+    let (linked, regular): (Vec<_>, Vec<_>) = to_copy
+        .iter()
+        .partition(|(rel_path, _, _)| hardlinks.contains_key(rel_path));
+    for (rel_path, nas_file_path, local_path) in regular {
+        copy_file(nas_file_path, local_path)
+            .with_context(|| format!("Failed to copy {}", rel_path.display()))?;
+        println!("Copied: {}", rel_path.display());
+    }
+    for (rel_path, nas_file_path, local_path) in linked {
+        let leader = local_file_path(config, &hardlinks[rel_path])?;
+        if link_or_copy(&leader, nas_file_path, local_path)
+            .with_context(|| format!("Failed to copy {}", rel_path.display()))?
+        {
+            println!("Linked: {}", rel_path.display());
+        } else {
+            println!("Copied: {}", rel_path.display());
         }
-        fs::copy(nas_file_path, local_path)
-            .with_context(|| format!("Failed to copy {}", rel_path))?;
-        println!("Copied: {}", rel_path);
+    }
+    for (rel_path, local_path, merged) in &to_merge {
+        fs::write(local_path, merged)
+            .with_context(|| format!("Failed to write {}", rel_path.display()))?;
+        println!("Merged: {}", rel_path.display());
     }
 
     // Perform deletions
     for (rel_path, local_path) in &to_delete {
         fs::remove_file(local_path)
-            .with_context(|| format!("Failed to delete {}", rel_path))?;
-        println!("Deleted: {}", rel_path);
+            .with_context(|| format!("Failed to delete {}", rel_path.display()))?;
+        println!("Deleted: {}", rel_path.display());
     }
 
-    // Save manifest
+    if config.common_dir.is_dir() {
+        create_git_skeleton(&config.common_dir)?;
+    }
+    if !pruned.is_empty() {
+        restore_needed_objects(&config.git_root, &tips_before, pruned)?;
+    }
+    update_holders(config, &mut new_manifest)?;
+
+    if config.verify_pull != VerifyMode::Off && has_git_dir(&config.git_root) {
+        let problems = verify_repository(&config.git_root)?;
+        if problems.is_empty() {
+            println!("Repository verified");
+        } else {
+            eprintln!("Repository check failed after pull:");
+            for problem in &problems {
+                eprintln!("  {}", problem);
+            }
+            if let Some(backup) = &backup {
+                if backup.saved.is_empty() && backup.created.is_empty() {
+                    bail!("This pull changed no files; the problems were there before it");
+                }
+                restore_backup(&config.git_root, backup)?;
+                bail!("Restored the files changed by this pull; the NAS copy may be incomplete");
+            }
+        }
+    }
+    drop(backup);
+
+    refresh_moved_indexes(&config.git_root, &heads_before)?;
+
+    if config.export_work
+        && has_git_dir(&config.git_root)
+        && let Err(err) = import_work(config)
+    {
+        eprintln!("Warning: cannot restore staged changes and stashes: {:#}", err);
+    }
+
+    // Save manifest, keeping the record of the last push
+    new_manifest.pushed_at = manifest.pushed_at;
+    new_manifest.host = manifest.host.clone();
     save_manifest(&config.nas_path, &new_manifest)?;
+    record_extras_branch(config)?;
 
-    let total_changes = to_copy.len() + to_delete.len();
+    let total_changes = to_copy.len() + to_merge.len() + to_delete.len();
     if total_changes == 0 {
         println!("Already up to date.");
     } else {
         println!(
             "Pull complete: {} copied, {} deleted",
-            to_copy.len(),
+            to_copy.len() + to_merge.len(),
             to_delete.len()
         );
     }
+    report_missing_lfs_objects(config);
 
     Ok(())
 }
 
-fn cmd_status() -> Result<()> {
-    let config = get_config()?;
-    let sync_files = get_sync_files(&config)?;
-    let manifest = load_manifest(&config.nas_path)?;
-
-    println!("Git root: {}", config.git_root.display());
-    println!("NAS path: {}", config.nas_path.display());
-    println!("Synced files: {}", sync_files.len());
-    println!("Additional files: {}", config.additional_files.len());
-    println!("Manifest entries: {}", manifest.files.len());
+/// Where exported staged changes come from and who has them already.
+#[derive(Debug, Serialize, Deserialize)]
+struct StagedInfo {
+    /// `HEAD` the patch was made against; it applies only on top of that
+    base: String,
+    /// Host that pushed the patch
+    host: String,
+    /// Hosts that have these changes staged, including the pushing one, so a
+    /// pull never stages them again after they were unstaged or committed
+    applied_by: Vec<String>,
+}
 
-    let mut local_only = 0;
-    let mut nas_only = 0;
-    let mut modified = 0;
-    let mut in_sync = 0;
+fn load_staged_info(path: &Path) -> Option<StagedInfo> {
+    let content = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
 
-    let sync_files_set: HashSet<_> = sync_files.iter().cloned().collect();
+fn save_staged_info(path: &Path, info: &StagedInfo) -> Result<()> {
+    let content = serde_json::to_string_pretty(info)?;
+    fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))
+}
 
-    for rel_path in &sync_files {
-        let local_path = config.git_root.join(rel_path);
-        let nas_file_path = config.nas_path.join(rel_path);
+/// Writes the staged changes as a binary patch and the stashes as a bundle to
+/// the NAS, replacing whatever the last push left there.
+fn export_work(config: &Config) -> Result<()> {
+    let root = &config.git_root;
+    let work_dir = config.nas_path.join(WORK_DIR);
+    fs::create_dir_all(&work_dir).with_context(|| format!("Failed to create {}", work_dir.display()))?;
 
-        if !local_path.exists() {
-            continue;
+    // The patch must stay byte for byte what git wrote
+    let output = Command::new("git")
+        .current_dir(root)
+        .args(["diff", "--cached", "--binary"])
+        .output()
+        .context("Failed to run git diff")?;
+    if !output.status.success() {
+        bail!("git diff failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let staged_path = work_dir.join(STAGED_PATCH);
+    let info_path = work_dir.join(STAGED_INFO);
+    if output.stdout.is_empty() {
+        for path in [&staged_path, &info_path] {
+            if path.exists() {
+                fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
         }
-
-        if !nas_file_path.exists() {
-            local_only += 1;
-        } else {
-            let local_hash = hash_file(&local_path)?;
+    } else {
+        let base = git_output(root, &["rev-parse", "--verify", "--quiet", "HEAD"]).unwrap_or_default();
+        let existing = load_staged_info(&info_path)
+            .filter(|info| info.base == base && fs::read(&staged_path).ok().as_ref() == Some(&output.stdout));
+        match existing {
+            // The same changes staged here too, e.g. after pulling them
+            Some(mut info) => {
+                if !info.applied_by.contains(&config.host) {
+                    info.applied_by.push(config.host.clone());
+                    save_staged_info(&info_path, &info)?;
+                }
+            }
+            None => {
+                fs::write(&staged_path, &output.stdout)
+                    .with_context(|| format!("Failed to write {}", staged_path.display()))?;
+                let info = StagedInfo {
+                    base,
+                    host: config.host.clone(),
+                    applied_by: vec![config.host.clone()],
+                };
+                save_staged_info(&info_path, &info)?;
+                println!("Exported: staged changes");
+            }
+        }
+    }
+
+    let list = git_output(root, &["stash", "list", "--format=%H %gs"])?;
+    let list_path = work_dir.join(STASH_LIST);
+    let bundle_path = work_dir.join(STASH_BUNDLE);
+    if list.is_empty() {
+        for path in [&list_path, &bundle_path] {
+            if path.exists() {
+                fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        return Ok(());
+    }
+    if fs::read_to_string(&list_path).is_ok_and(|existing| existing.trim() == list) && bundle_path.exists() {
+        return Ok(());
+    }
+
+    // Stashes are only reachable through the reflog, which a bundle cannot carry
+    let refs: Vec<String> = (0..list.lines().count())
+        .map(|index| format!("{}/{}", STASH_TEMP_REFS, index))
+        .collect();
+    let result = refs
+        .iter()
+        .zip(list.lines())
+        .try_for_each(|(name, line)| {
+            let commit = line.split(' ').next().unwrap_or_default();
+            git_output(root, &["update-ref", name, commit]).map(drop)
+        })
+        .and_then(|()| {
+            run_git(
+                Command::new("git")
+                    .current_dir(root)
+                    .args(["bundle", "create", "--quiet"])
+                    .arg(&bundle_path)
+                    .args(&refs)
+                    .args(["--not", "--branches", "--tags", "--remotes"]),
+            )
+        });
+    for name in &refs {
+        let _ = git_output(root, &["update-ref", "-d", name]);
+    }
+    result.context("Failed to bundle stashes")?;
+
+    fs::write(&list_path, format!("{}\n", list))
+        .with_context(|| format!("Failed to write {}", list_path.display()))?;
+    println!("Exported: {} stashes", refs.len());
+    Ok(())
+}
+
+/// Applies the staged changes and stashes exported by the last push. Staged
+/// changes are applied once per host, only on top of the commit they were made
+/// against and only into an index with nothing staged. Stashes already present
+/// are not stored twice.
+fn import_work(config: &Config) -> Result<()> {
+    let root = &config.git_root;
+    let work_dir = config.nas_path.join(WORK_DIR);
+
+    let staged_path = work_dir.join(STAGED_PATCH);
+    let info_path = work_dir.join(STAGED_INFO);
+    let head = git_output(root, &["rev-parse", "--verify", "--quiet", "HEAD"]).unwrap_or_default();
+    if staged_path.exists()
+        && let Some(mut info) = load_staged_info(&info_path)
+        && info.base == head
+        && !info.applied_by.contains(&config.host)
+    {
+        let patch = fs::read(&staged_path).with_context(|| format!("Failed to read {}", staged_path.display()))?;
+        let local = git_output(root, &["diff", "--cached", "--binary"])?;
+        let applied = if local.is_empty() {
+            run_git(
+                Command::new("git")
+                    .current_dir(root)
+                    .args(["apply", "--cached"])
+                    .arg(&staged_path),
+            )
+            .context("Staged changes from the NAS do not apply")?;
+            println!("Applied: staged changes from {}", info.host);
+            true
+        } else if local != String::from_utf8_lossy(&patch).trim() {
+            eprintln!(
+                "Warning: keeping the locally staged changes; the ones {} pushed are in {}",
+                info.host,
+                staged_path.display()
+            );
+            false
+        } else {
+            true
+        };
+        if applied {
+            info.applied_by.push(config.host.clone());
+            save_staged_info(&info_path, &info)?;
+        }
+    }
+
+    let list_path = work_dir.join(STASH_LIST);
+    let bundle_path = work_dir.join(STASH_BUNDLE);
+    if !list_path.exists() || !bundle_path.exists() {
+        return Ok(());
+    }
+    let list = fs::read_to_string(&list_path).with_context(|| format!("Failed to read {}", list_path.display()))?;
+    let local = git_output(root, &["stash", "list", "--format=%H"])?;
+    let local: HashSet<_> = local.lines().collect();
+    let missing: Vec<_> = list
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(commit, _)| !local.contains(commit))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    run_git(
+        Command::new("git")
+            .current_dir(root)
+            .args(["bundle", "unbundle"])
+            .arg(&bundle_path),
+    )
+    .context("Failed to unpack the stashes pushed to the NAS")?;
+    // Oldest first, so the order matches the pushing side
+    for (commit, message) in missing.iter().rev() {
+        git_output(root, &["stash", "store", "-m", message, commit])?;
+    }
+    println!("Applied: {} stashes", missing.len());
+    Ok(())
+}
+
+/// Creates the directories git expects in every repository. They are often
+/// empty, e.g. `refs/heads` once `git gc` has packed all refs, and empty
+/// directories never make it through the file sync.
+fn create_git_skeleton(git_dir: &Path) -> Result<()> {
+    for dir in ["objects", "refs/heads", "refs/tags"] {
+        let path = git_dir.join(dir);
+        fs::create_dir_all(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Pushes branches and tags to the bare mirror on the NAS. Only fast-forwards
+/// are accepted, so history pushed from another machine is never lost.
+fn push_git_mirror(config: &Config) -> Result<()> {
+    let mirror = config.nas_path.join(GIT_MIRROR_DIR);
+    if !mirror.exists() {
+        git_output(&config.nas_path, &["init", "--quiet", "--bare", GIT_MIRROR_DIR])?;
+    }
+    let status = Command::new("git")
+        .current_dir(&config.git_root)
+        .args(["push", "--quiet"])
+        .arg(&mirror)
+        .args(["refs/heads/*:refs/heads/*", "refs/tags/*:refs/tags/*"])
+        .status()
+        .context("Failed to run git push")?;
+    if !status.success() {
+        bail!("Pushing history to {} failed; pull first if a branch has moved on the NAS", mirror.display());
+    }
+
+    // Remember the checked-out branch so a fresh clone can check it out too
+    if let Ok(head) = git_output(&config.git_root, &["symbolic-ref", "--quiet", "HEAD"]) {
+        git_output(&mirror, &["symbolic-ref", "HEAD", &head])?;
+    }
+
+    println!("Pushed history to {}", GIT_MIRROR_DIR);
+    Ok(())
+}
+
+/// Fetches from the bare mirror on the NAS and fast-forwards local branches.
+/// Branches that have diverged are left alone and reported.
+fn pull_git_mirror(config: &Config) -> Result<()> {
+    let mirror = config.nas_path.join(GIT_MIRROR_DIR);
+    if !mirror.is_dir() {
+        println!("No history mirror on NAS yet, skipping git fetch");
+        return Ok(());
+    }
+    if !has_git_dir(&config.git_root) {
+        git_output(&config.git_root, &["init", "--quiet"])?;
+    }
+    let unborn = git_output(&config.git_root, &["rev-parse", "--verify", "--quiet", "HEAD"]).is_err();
+
+    let status = Command::new("git")
+        .current_dir(&config.git_root)
+        .args(["fetch", "--quiet"])
+        .arg(&mirror)
+        .args(["+refs/heads/*:refs/remotes/local-sync/*", "refs/tags/*:refs/tags/*"])
+        .status()
+        .context("Failed to run git fetch")?;
+    if !status.success() {
+        bail!("Fetching history from {} failed", mirror.display());
+    }
+
+    if unborn && let Ok(head) = git_output(&mirror, &["symbolic-ref", "--quiet", "HEAD"]) {
+        git_output(&config.git_root, &["symbolic-ref", "HEAD", &head])?;
+    }
+    let fetched = git_output(
+        &config.git_root,
+        &["for-each-ref", "--format=%(refname:lstrip=3) %(objectname)", "refs/remotes/local-sync/"],
+    )?;
+    for line in fetched.lines() {
+        let Some((branch, remote_oid)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let local_ref = format!("refs/heads/{}", branch);
+        let local_oid = git_output(&config.git_root, &["rev-parse", "--verify", "--quiet", &local_ref]).ok();
+
+        match local_oid {
+            Some(local_oid) if local_oid == remote_oid => continue,
+            Some(local_oid) => {
+                if is_ancestor(&config.git_root, remote_oid, &local_oid, None)? {
+                    continue;
+                }
+                if !is_ancestor(&config.git_root, &local_oid, remote_oid, None)? {
+                    eprintln!(
+                        "Warning: branch {} has diverged from the NAS; kept local, NAS version is refs/remotes/local-sync/{}",
+                        branch, branch
+                    );
+                    continue;
+                }
+                git_output(&config.git_root, &["update-ref", &local_ref, remote_oid, &local_oid])?;
+                println!("Fast-forwarded: {}", branch);
+            }
+            None => {
+                git_output(&config.git_root, &["update-ref", &local_ref, remote_oid, ""])?;
+                println!("Created branch: {}", branch);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `HEAD` resolves and all objects are reachable, returning the
+/// problems found.
+fn verify_repository(git_root: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    // A repository without any refs has no commit to point at yet
+    let has_refs = !git_output(git_root, &["for-each-ref", "--count=1", "--format=%(refname)"])?.is_empty();
+    if has_refs && git_output(git_root, &["rev-parse", "--verify", "--quiet", "HEAD^{commit}"]).is_err() {
+        problems.push("HEAD does not resolve to a commit".to_string());
+    }
+
+    let output = Command::new("git")
+        .current_dir(git_root)
+        .args(["fsck", "--connectivity-only", "--no-progress", "--no-dangling"])
+        .output()
+        .context("Failed to run git fsck")?;
+    if !output.status.success() {
+        let report = String::from_utf8_lossy(&output.stderr).to_string() + &String::from_utf8_lossy(&output.stdout);
+        problems.extend(report.lines().filter(|line| !line.is_empty()).map(String::from));
+        if problems.is_empty() {
+            problems.push("git fsck failed".to_string());
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Local files saved before a pull changes them. The saved copies are removed
+/// when it is dropped, however the pull ends.
+struct Backup {
+    dir: PathBuf,
+    /// Files that existed, with the path of their saved copy
+    saved: Vec<(PathBuf, PathBuf)>,
+    /// Files the pull creates, removed on restore
+    created: Vec<PathBuf>,
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn backup_files<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> Result<Backup> {
+    let dir = std::env::temp_dir().join(format!("local-sync-backup-{}", std::process::id()));
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let mut backup = Backup {
+        dir,
+        saved: Vec::new(),
+        created: Vec::new(),
+    };
+    for path in paths {
+        if path.exists() {
+            let copy = backup.dir.join(backup.saved.len().to_string());
+            fs::copy(path, &copy).with_context(|| format!("Failed to back up {}", path.display()))?;
+            backup.saved.push((path.clone(), copy));
+        } else {
+            backup.created.push(path.clone());
+        }
+    }
+
+    Ok(backup)
+}
+
+fn restore_backup(root: &Path, backup: &Backup) -> Result<()> {
+    for path in &backup.created {
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            cleanup_empty_dirs(root, path)?;
+        }
+    }
+    for (path, copy) in &backup.saved {
+        copy_file(copy, path).with_context(|| format!("Failed to restore {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Maps the project and each checked-out submodule, relative to `root`, to
+/// its current `HEAD` commit.
+fn checkout_heads(root: &Path) -> HashMap<PathBuf, String> {
+    let mut heads = HashMap::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(checkout) = pending.pop() {
+        let dir = root.join(&checkout);
+        let Some(head) = head_commit(&dir) else {
+            continue;
+        };
+        heads.insert(checkout.clone(), head);
+        for submodule in get_gitlinks(&dir).unwrap_or_default() {
+            pending.push(checkout.join(submodule));
+        }
+    }
+    heads
+}
+
+/// Rebuilds the index of every checkout whose `HEAD` differs from `before`.
+/// A parent is refreshed before its index is asked for submodules.
+fn refresh_moved_indexes(root: &Path, before: &HashMap<PathBuf, String>) -> Result<()> {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(checkout) = pending.pop() {
+        let dir = root.join(&checkout);
+        let Some(head) = head_commit(&dir) else {
+            continue;
+        };
+        if before.get(&checkout) != Some(&head) {
+            git_output(&dir, &["reset", "--quiet"])?;
+            if checkout.as_os_str().is_empty() {
+                println!("Updated index for the new HEAD");
+            } else {
+                println!("Updated index for the new HEAD in {}", checkout.display());
+            }
+        }
+        for submodule in get_gitlinks(&dir).unwrap_or_default() {
+            pending.push(checkout.join(submodule));
+        }
+    }
+    Ok(())
+}
+
+fn head_commit(git_root: &Path) -> Option<String> {
+    if !has_git_dir(git_root) {
+        return None;
+    }
+    git_output(git_root, &["rev-parse", "--verify", "--quiet", "HEAD"]).ok()
+}
+
+/// Checks commit ancestry, optionally also looking up objects in another
+/// object directory. A commit missing from both is an error.
+fn is_ancestor(git_root: &Path, ancestor: &str, descendant: &str, extra_objects: Option<&Path>) -> Result<bool> {
+    let mut command = Command::new("git");
+    command
+        .current_dir(git_root)
+        .args(["merge-base", "--is-ancestor", ancestor, descendant]);
+    if let Some(objects) = extra_objects {
+        command.env("GIT_ALTERNATE_OBJECT_DIRECTORIES", objects);
+    }
+    let output = command.output().context("Failed to run git merge-base")?;
+
+    // Anything but a yes or no means a commit is missing, which must not pass
+    // for divergence
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => bail!(
+            "Cannot compare commits {} and {}: {}",
+            ancestor,
+            descendant,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Directories inside `.git` that only ever grow: objects are immutable and
+/// refs kept from diverged branches must survive until someone looks at them.
+/// Files in them are never deleted from the NAS; a host that removes them
+/// locally is recorded in the manifest instead, so it doesn't get them back.
+const GIT_ADDITIVE_DIRS: &[&str] = &[".git/objects", ".git/lfs/objects", ".git/refs/local-sync"];
+
+fn is_git_additive(rel_path: &Path) -> bool {
+    GIT_ADDITIVE_DIRS.iter().any(|dir| rel_path.starts_with(dir))
+}
+
+/// How an incoming branch ref relates to the one it would overwrite.
+enum RefUpdate {
+    Same,
+    FastForward,
+    /// The destination already contains the incoming commit
+    Behind,
+    Diverged,
+}
+
+/// Compares an incoming loose branch ref with the same branch at the
+/// destination, loose (`existing`) or packed (`packed_refs`), by commit
+/// ancestry. Returns `None` for paths that are not branch refs, branches
+/// missing at the destination and files that do not hold a plain object id,
+/// which then go through the regular file handling.
+fn compare_refs(
+    config: &Config,
+    rel_path: &Path,
+    incoming: &Path,
+    existing: &Path,
+    packed_refs: &Path,
+) -> Result<Option<RefUpdate>> {
+    if !is_branch_ref(rel_path) {
+        return Ok(None);
+    }
+
+    let read_oid = |path: &Path| -> Result<Option<String>> {
+        let oid = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .trim()
+            .to_string();
+        let valid = matches!(oid.len(), 40 | 64) && oid.bytes().all(|b| b.is_ascii_hexdigit());
+        Ok(valid.then_some(oid))
+    };
+    let existing_oid = if existing.exists() {
+        read_oid(existing)?
+    } else if packed_refs.exists() {
+        let name: Vec<_> = rel_path.iter().skip(1).map(|part| part.to_string_lossy()).collect();
+        parse_packed_refs(packed_refs)?
+            .refs
+            .remove(&name.join("/"))
+            .map(|(oid, _)| oid)
+    } else {
+        None
+    };
+    let (Some(incoming_oid), Some(existing_oid)) = (read_oid(incoming)?, existing_oid) else {
+        return Ok(None);
+    };
+
+    if incoming_oid == existing_oid {
+        return Ok(Some(RefUpdate::Same));
+    }
+
+    // The two sides' objects may not have been copied yet, so look in both
+    let nas_objects = config.nas_path.join(".git/objects");
+    let is_ancestor = |ancestor: &str, descendant: &str| {
+        is_ancestor(&config.git_root, ancestor, descendant, Some(&nas_objects))
+            .with_context(|| format!("Cannot resolve {}", rel_path.display()))
+    };
+    let update = if is_ancestor(&existing_oid, &incoming_oid)? {
+        RefUpdate::FastForward
+    } else if is_ancestor(&incoming_oid, &existing_oid)? {
+        RefUpdate::Behind
+    } else {
+        RefUpdate::Diverged
+    };
+    Ok(Some(update))
+}
+
+/// Records a NAS branch ref that a push leaves in place. It may only be packed.
+fn keep_nas_ref(manifest: &Manifest, new_manifest: &mut Manifest, rel_path: &Path, nas_file_path: &Path) -> Result<()> {
+    if nas_file_path.exists() {
+        new_manifest.files.insert(
+            rel_path.to_path_buf(),
+            FileEntry {
+                hash: hash_file(nas_file_path)?,
+                synced_at: chrono::Utc::now(),
+                hardlink: None,
+                host: manifest.files.get(rel_path).and_then(|entry| entry.host.clone()),
+                pruned_by: Vec::new(),
+                held_by: Vec::new(),
+            },
+        );
+    }
+    Ok(())
+}
+
+fn is_branch_ref(rel_path: &Path) -> bool {
+    rel_path.starts_with(".git/refs/heads")
+}
+
+const PACKED_REFS: &str = ".git/packed-refs";
+
+/// Ref files whose pushing host is recorded in the manifest.
+fn is_ref_file(rel_path: &Path) -> bool {
+    is_branch_ref(rel_path) || rel_path == Path::new(PACKED_REFS)
+}
+
+/// Loose objects, pack files and LFS objects are named after their content
+/// and never rewritten, only added or removed by `git gc` or `git lfs prune`.
+fn is_immutable_object(rel_path: &Path) -> bool {
+    if lfs_object_id(rel_path).is_some() {
+        return true;
+    }
+    let Ok(rest) = rel_path.strip_prefix(".git/objects") else {
+        return false;
+    };
+    let parts: Vec<_> = rest.iter().map(|part| part.to_string_lossy()).collect();
+    match parts.as_slice() {
+        [dir, name] if dir == "pack" => name.starts_with("pack-"),
+        [dir, _] => dir.len() == 2 && dir.bytes().all(|b| b.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+/// Records which hosts have each additive file: a host that has it now holds
+/// it, and a host that held it but no longer has it has pruned it.
+fn update_holders(config: &Config, manifest: &mut Manifest) -> Result<()> {
+    for (rel_path, entry) in &mut manifest.files {
+        if !is_git_additive(rel_path) {
+            continue;
+        }
+        if local_file_path(config, rel_path)?.exists() {
+            if !entry.held_by.contains(&config.host) {
+                entry.held_by.push(config.host.clone());
+            }
+            entry.pruned_by.retain(|host| *host != config.host);
+        } else if entry.held_by.contains(&config.host) && !entry.pruned_by.contains(&config.host) {
+            entry.pruned_by.push(config.host.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Object ids of every ref and `HEAD`.
+fn ref_tips(root: &Path) -> Vec<String> {
+    let refs = git_output(root, &["for-each-ref", "--format=%(objectname)"]).unwrap_or_default();
+    let head = git_output(root, &["rev-parse", "--verify", "--quiet", "HEAD"]).unwrap_or_default();
+    refs.lines()
+        .chain(head.lines())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Copies back pruned object files that refs written by this pull still need,
+/// e.g. objects this host pruned that another host's new commits reference.
+fn restore_needed_objects(
+    root: &Path,
+    tips_before: &[String],
+    mut pruned: Vec<(PathBuf, PathBuf, PathBuf)>,
+) -> Result<()> {
+    loop {
+        let missing = missing_objects(root, tips_before)?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // Loose objects are named after their id; for packs there is no telling
+        // which holds what, so all of them come back if a loose one won't do
+        let loose_id = |rel_path: &Path| {
+            let parts: Vec<_> = rel_path.iter().map(|part| part.to_string_lossy()).collect();
+            match parts.as_slice() {
+                [.., dir, name] if dir.len() == 2 => Some(format!("{}{}", dir, name)),
+                _ => None,
+            }
+        };
+        let (mut restore, mut rest): (Vec<_>, Vec<_>) = pruned
+            .into_iter()
+            .partition(|(rel_path, _, _)| loose_id(rel_path).is_some_and(|id| missing.contains(&id)));
+        if restore.is_empty() {
+            (restore, rest) = rest
+                .into_iter()
+                .partition(|(rel_path, _, _)| rel_path.starts_with(".git/objects/pack"));
+        }
+        if restore.is_empty() {
+            eprintln!("Warning: {} objects are missing and not on the NAS", missing.len());
+            return Ok(());
+        }
+
+        for (rel_path, nas_file_path, local_path) in &restore {
+            copy_file(nas_file_path, local_path)
+                .with_context(|| format!("Failed to copy {}", rel_path.display()))?;
+            println!("Restored: {}", rel_path.display());
+        }
+        pruned = rest;
+    }
+}
+
+/// Lists the objects reachable from the current refs but not from
+/// `tips_before` that the repository doesn't have.
+fn missing_objects(root: &Path, tips_before: &[String]) -> Result<HashSet<String>> {
+    let tips = ref_tips(root);
+    let all: Vec<&str> = tips.iter().chain(tips_before).map(String::as_str).collect();
+    let present = present_objects(root, &all)?;
+
+    // Tips that are missing themselves can't be walked
+    let mut missing: HashSet<String> = tips.iter().filter(|tip| !present.contains(*tip)).cloned().collect();
+    let mut input = String::new();
+    for tip in tips.iter().filter(|tip| present.contains(*tip)) {
+        input.push_str(&format!("{}\n", tip));
+    }
+    for tip in tips_before.iter().filter(|tip| present.contains(*tip)) {
+        input.push_str(&format!("^{}\n", tip));
+    }
+    if input.is_empty() {
+        return Ok(missing);
+    }
+
+    let mut child = Command::new("git")
+        .current_dir(root)
+        .args(["rev-list", "--objects", "--missing=print", "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to run git rev-list")?;
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().context("Failed to run git rev-list")?;
+    writer.join().unwrap().context("Failed to write to git rev-list")?;
+    if !output.status.success() {
+        bail!("git rev-list failed");
+    }
+
+    // Missing objects are listed as "?<id>"
+    missing.extend(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.strip_prefix('?'))
+            .map(str::to_string),
+    );
+    Ok(missing)
+}
+
+/// Finds the object files in the manifest that are missing locally but whose
+/// objects the local repository has anyway, typically after `git gc` repacked
+/// them here. A pack file counts only if every object in its index is present.
+fn find_repacked_objects(config: &Config, manifest: &Manifest) -> Result<HashSet<PathBuf>> {
+    let mut loose = Vec::new();
+    let mut packs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for rel_path in manifest.files.keys() {
+        if !is_immutable_object(rel_path)
+            || lfs_object_id(rel_path).is_some()
+            || local_file_path(config, rel_path)?.exists()
+        {
+            continue;
+        }
+        let parts: Vec<_> = rel_path.iter().map(|part| part.to_string_lossy()).collect();
+        match parts.as_slice() {
+            [.., dir, _] if dir == "pack" => {
+                packs.entry(rel_path.with_extension("idx")).or_default().push(rel_path.clone());
+            }
+            [.., dir, name] => loose.push((format!("{}{}", dir, name), rel_path.clone())),
+            _ => {}
+        }
+    }
+    if loose.is_empty() && packs.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let mut pack_objects = Vec::new();
+    for (idx, files) in packs {
+        let nas_idx = safe_join(&config.nas_path, &idx)?;
+        let Ok(idx_file) = fs::File::open(&nas_idx) else {
+            continue;
+        };
+        let output = Command::new("git")
+            .current_dir(&config.git_root)
+            .arg("show-index")
+            .stdin(idx_file)
+            .output()
+            .context("Failed to run git show-index")?;
+        if !output.status.success() {
+            continue;
+        }
+        let ids: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1).map(str::to_string))
+            .collect();
+        if !ids.is_empty() {
+            pack_objects.push((ids, files));
+        }
+    }
+
+    let wanted: Vec<&str> = loose
+        .iter()
+        .map(|(id, _)| id.as_str())
+        .chain(pack_objects.iter().flat_map(|(ids, _)| ids.iter().map(String::as_str)))
+        .collect();
+    let present = present_objects(&config.git_root, &wanted)?;
+
+    let mut repacked: HashSet<PathBuf> = loose
+        .into_iter()
+        .filter(|(id, _)| present.contains(id))
+        .map(|(_, rel_path)| rel_path)
+        .collect();
+    for (ids, files) in pack_objects {
+        if ids.iter().all(|id| present.contains(id)) {
+            repacked.extend(files);
+        }
+    }
+    Ok(repacked)
+}
+
+/// Returns which of the given object ids the repository at `root` has.
+fn present_objects(root: &Path, ids: &[&str]) -> Result<HashSet<String>> {
+    let mut child = Command::new("git")
+        .current_dir(root)
+        .args(["cat-file", "--batch-check=%(objectname)"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run git cat-file")?;
+
+    // Feed the ids from another thread so a full output pipe can't block us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = ids.join("\n") + "\n";
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().context("Failed to run git cat-file")?;
+    writer.join().unwrap().context("Failed to write to git cat-file")?;
+    if !output.status.success() {
+        bail!("git cat-file failed");
+    }
+
+    // Missing objects are reported as "<id> missing"
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.contains(' '))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns the object id of a `.git/lfs/objects/<aa>/<bb>/<oid>` path.
+fn lfs_object_id(rel_path: &Path) -> Option<String> {
+    let rest = rel_path.strip_prefix(".git/lfs/objects").ok()?;
+    if rest.iter().count() != 3 {
+        return None;
+    }
+    let oid = rest.file_name()?.to_str()?;
+    (oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit())).then(|| oid.to_string())
+}
+
+/// Checks whether the project uses Git LFS.
+fn uses_lfs(config: &Config) -> bool {
+    config.common_dir.join("lfs").is_dir()
+        || fs::read_to_string(config.git_root.join(".gitattributes"))
+            .is_ok_and(|attributes| attributes.contains("filter=lfs"))
+}
+
+/// Lists the LFS pointers at `rev` (the working tree if `None`) as object id and path.
+fn lfs_pointers(git_root: &Path, rev: Option<&str>) -> Result<Vec<(String, String)>> {
+    let mut args = vec!["lfs", "ls-files", "--long"];
+    args.extend(rev);
+    let output = git_output(git_root, &args).context("Failed to list LFS files; is git-lfs installed?")?;
+
+    // Lines look like `<oid> * <path>`, with `-` instead of `*` for bare pointers
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let oid = parts.next()?;
+            parts.next()?;
+            Some((oid.to_string(), parts.next()?.to_string()))
+        })
+        .collect())
+}
+
+/// Collects the LFS objects referenced at the tips of remote-tracking
+/// branches. Pushing a branch uploads its LFS objects, so the server has them.
+fn lfs_remote_objects(git_root: &Path) -> Result<HashSet<String>> {
+    let refs = git_output(git_root, &["for-each-ref", "--format=%(refname)", "refs/remotes"])?;
+    let mut objects = HashSet::new();
+    for rev in refs.lines() {
+        objects.extend(lfs_pointers(git_root, Some(rev))?.into_iter().map(|(oid, _)| oid));
+    }
+    Ok(objects)
+}
+
+/// Warns about LFS pointers in the working tree whose objects are not present.
+fn report_missing_lfs_objects(config: &Config) {
+    if !uses_lfs(config) {
+        return;
+    }
+    let pointers = match lfs_pointers(&config.git_root, None) {
+        Ok(pointers) => pointers,
+        Err(err) => {
+            eprintln!("Warning: cannot check LFS objects: {:#}", err);
+            return;
+        }
+    };
+
+    let missing: Vec<_> = pointers
+        .iter()
+        .filter(|(oid, _)| {
+            oid.len() < 4
+                || !config
+                    .common_dir
+                    .join("lfs/objects")
+                    .join(&oid[0..2])
+                    .join(&oid[2..4])
+                    .join(oid)
+                    .exists()
+        })
+        .collect();
+    if !missing.is_empty() {
+        eprintln!("Missing LFS objects (run 'git lfs pull' to download them):");
+        for (_, path) in missing {
+            eprintln!("  {}", path);
+        }
+    }
+}
+
+/// Contents of a `packed-refs` file: header traits and each ref's object id
+/// with its peeled (`^`) id, if any.
+struct PackedRefs {
+    traits: Vec<String>,
+    refs: BTreeMap<String, (String, Option<String>)>,
+}
+
+fn parse_packed_refs(path: &Path) -> Result<PackedRefs> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut packed = PackedRefs {
+        traits: Vec::new(),
+        refs: BTreeMap::new(),
+    };
+    let mut last_ref = None;
+
+    for line in content.lines() {
+        if let Some(traits) = line.strip_prefix("# pack-refs with:") {
+            packed.traits = traits.split_whitespace().map(String::from).collect();
+        } else if let Some(peeled) = line.strip_prefix('^') {
+            let Some(entry) = last_ref.as_ref().and_then(|name| packed.refs.get_mut(name)) else {
+                bail!("Peeled line without a ref in {}", path.display());
+            };
+            entry.1 = Some(peeled.to_string());
+        } else if let Some((oid, name)) = line.split_once(' ') {
+            packed.refs.insert(name.to_string(), (oid.to_string(), None));
+            last_ref = Some(name.to_string());
+        } else if !line.is_empty() {
+            bail!("Unexpected line in {}: {}", path.display(), line);
+        }
+    }
+
+    Ok(packed)
+}
+
+/// Merges two `packed-refs` files into their union. Refs present on both sides
+/// follow the loose ref rules: fast-forwards win, otherwise the existing value
+/// is kept and a diverged incoming branch is stored under `refs/local-sync/<host>/`.
+fn merge_packed_refs(config: &Config, incoming: &Path, existing: &Path, host: &str) -> Result<String> {
+    let incoming = parse_packed_refs(incoming)?;
+    let mut merged = parse_packed_refs(existing)?;
+    merged.traits.retain(|name| incoming.traits.contains(name));
+
+    let nas_objects = config.nas_path.join(".git/objects");
+    for (name, (oid, peeled)) in incoming.refs {
+        let Some((existing_oid, _)) = merged.refs.get(&name) else {
+            merged.refs.insert(name, (oid, peeled));
+            continue;
+        };
+        if *existing_oid == oid {
+            continue;
+        }
+
+        if is_ancestor(&config.git_root, existing_oid, &oid, Some(&nas_objects))? {
+            merged.refs.insert(name, (oid, peeled));
+        } else if let Some(branch) = name.strip_prefix("refs/heads/")
+            && !is_ancestor(&config.git_root, &oid, existing_oid, Some(&nas_objects))?
+        {
+            let kept = format!("refs/local-sync/{}/{}", host, branch);
+            if merged.refs.get(&kept).is_none_or(|(kept_oid, _)| *kept_oid != oid) {
+                println!("Diverged: {} in packed-refs (kept as {})", name, kept);
+                merged.refs.insert(kept, (oid, peeled));
+            }
+        }
+    }
+
+    let mut content = format!("# pack-refs with: {} \n", merged.traits.join(" "));
+    for (name, (oid, peeled)) in &merged.refs {
+        content.push_str(&format!("{} {}\n", oid, name));
+        if let Some(peeled) = peeled {
+            content.push_str(&format!("^{}\n", peeled));
+        }
+    }
+    Ok(content)
+}
+
+/// Where a diverged branch from `host` is kept: `.git/refs/local-sync/<host>/<branch>`.
+fn kept_ref_path(rel_path: &Path, host: &str) -> Result<PathBuf> {
+    let branch = rel_path.strip_prefix(".git/refs/heads")?;
+    Ok(Path::new(".git/refs/local-sync").join(host).join(branch))
+}
+
+fn cmd_status(args: &[String]) -> Result<()> {
+    let config = get_config(option_value(args, "--target")?.as_deref())?;
+    let sync_files = get_sync_files(&config)?.files;
+    let manifest = load_manifest(&config.nas_path)?;
+
+    println!("Git root: {}", config.git_root.display());
+    println!("Host: {}", config.host);
+    println!("Target: {}", config.target_name);
+    println!("NAS path: {}", config.nas_path.display());
+    if config.git_transport == GitTransport::Mirror {
+        println!("Git transport: mirror ({})", config.nas_path.join(GIT_MIRROR_DIR).display());
+    }
+    if uses_lfs(&config) {
+        let mode = match config.lfs {
+            LfsMode::Sync => "synced",
+            LfsMode::SkipRemote => "synced unless available remotely",
+        };
+        println!("LFS objects: {}", mode);
+    }
+    println!("Synced files: {}", sync_files.len());
+    println!("Additional files: {}", config.additional_files.len());
+    if let Some(prefix) = &config.extras_prefix {
+        println!("Additional files on NAS: {}", prefix.display());
+    }
+    if config.export_work {
+        println!("Staged changes and stashes: exported to {}", config.nas_path.join(WORK_DIR).display());
+    }
+    println!("Manifest entries: {}", manifest.files.len());
+
+    let mut local_only = 0;
+    let mut nas_only = 0;
+    let mut modified = 0;
+    let mut in_sync = 0;
+
+    let sync_files_set: HashSet<_> = sync_files.iter().cloned().collect();
+
+    for rel_path in &sync_files {
+        let local_path = local_file_path(&config, rel_path)?;
+        let nas_file_path = safe_join(&config.nas_path, rel_path)?;
+
+        if !local_path.exists() {
+            continue;
+        }
+
+        if !nas_file_path.exists() {
+            local_only += 1;
+        } else {
+            let local_hash = hash_file(&local_path)?;
             let nas_hash = hash_file(&nas_file_path)?;
             if local_hash == nas_hash {
                 in_sync += 1;
             } else {
-                modified += 1;
+                modified += 1;
+            }
+        }
+    }
+
+    for rel_path in manifest.files.keys() {
+        if !sync_files_set.contains(rel_path) && !is_other_branch_extra(&config, rel_path) {
+            nas_only += 1;
+        }
+    }
+
+    println!();
+    println!("Status:");
+    println!("  In sync: {}", in_sync);
+    println!("  Modified: {}", modified);
+    println!("  Local only: {}", local_only);
+    println!("  NAS only: {}", nas_only);
+
+    if !config.additional_files.is_empty() {
+        println!();
+        println!("Additional files:");
+        for file in &config.additional_files {
+            println!("  +{}", file);
+        }
+    }
+
+    Ok(())
+}
+
+/// Project configuration file, committed alongside the project.
+const CONFIG_FILE: &str = ".local-sync.toml";
+
+/// Gitignore-style exclude patterns, applied on top of the config's excludes.
+const IGNORE_FILE: &str = ".localsyncignore";
+
+/// Pre-TOML configuration file: the NAS path on the first line followed by
+/// `+path` lines for additional files. Still read, but no longer written.
+const LEGACY_CONFIG_FILE: &str = ".local-sync";
+
+/// Name given to the target created by `init` and to single-target configs.
+const DEFAULT_TARGET: &str = "default";
+
+/// Machine-specific state, kept inside the git directory so it is never
+/// committed or synced.
+const LOCAL_CONFIG_FILE: &str = "local-sync.toml";
+
+/// Shared, committable settings. Targets may be defined here too (with
+/// per-host overrides), but are usually kept in the private local file.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ConfigFile {
+    #[serde(flatten)]
+    target_config: TargetsConfig,
+    /// Single unnamed target from earlier versions, read as the default target
+    #[serde(default, skip_serializing)]
+    target: Option<TargetSection>,
+    #[serde(default)]
+    include: IncludeSection,
+    #[serde(default)]
+    exclude: ExcludeSection,
+    #[serde(default)]
+    git: GitSection,
+    #[serde(default)]
+    options: OptionsSection,
+}
+
+/// Private settings for this checkout, stored in `.git/local-sync.toml`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct LocalConfigFile {
+    /// Identity of this machine, used instead of the system host name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    /// Branch whose additional files the working tree last pushed or pulled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extras_branch: Option<String>,
+    #[serde(flatten)]
+    target_config: TargetsConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct TargetsConfig {
+    /// Target used when no `--target` is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_target: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    targets: BTreeMap<String, TargetSection>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TargetSection {
+    /// Target directory. `~`, `$VAR`, `${VAR}` and `${VAR:-default}` are
+    /// expanded so the config can be shared between machines.
+    path: String,
+    /// Per-host replacements for `path`, keyed by host name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    hosts: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct IncludeSection {
+    /// Gitignored files, directories and gitignore-style glob patterns to
+    /// sync in addition to git's files. Patterns are re-evaluated on each sync;
+    /// file names are encoded like manifest paths (see `encode_path`).
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ExcludeSection {
+    /// Gitignore-style patterns for files that are never synced
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct GitSection {
+    /// Entries of `GIT_VOLATILE_PATTERNS` to sync anyway, e.g. `["/logs/"]`
+    #[serde(default)]
+    include: Vec<String>,
+    /// What to do with `.git/lfs/objects`
+    #[serde(default)]
+    lfs: LfsMode,
+    /// Carry staged changes and stashes as a patch and a bundle instead of
+    /// through `.git/index` and `refs/stash`
+    #[serde(default)]
+    export_work: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LfsMode {
+    /// Copy every LFS object to the NAS
+    #[default]
+    Sync,
+    /// Leave out objects referenced by remote-tracking branches, which the
+    /// LFS server already has
+    SkipRemote,
+}
+
+/// Files inside `.git` that are transient or specific to one machine. Syncing
+/// them causes constant conflicts or can leave a repository locked, so they
+/// are skipped unless named in the config's `[git] include` list. Objects,
+/// packs, refs, `HEAD`, `config` and hooks are always synced.
+const GIT_VOLATILE_PATTERNS: &[&str] = &[
+    // Lock files of a running git command
+    "*.lock",
+    // The staging area, tied to this checkout's file stat data
+    "/index",
+    "/sharedindex.*",
+    // Leftovers of the last merge, rebase, fetch or commit
+    "/ORIG_HEAD",
+    "/FETCH_HEAD",
+    "/AUTO_MERGE",
+    "/COMMIT_EDITMSG",
+    // Reflogs record this machine's history of ref updates
+    "/logs/",
+    // Other checkouts' private state, full of absolute paths
+    "/worktrees/",
+    // Housekeeping state and derived caches
+    "/gc.log",
+    "/gc.pid",
+    "/objects/info/commit-graph",
+    "/objects/info/commit-graphs/",
+    "/objects/pack/tmp_*",
+    "/objects/pack/.tmp-*",
+    "/fsmonitor--daemon/",
+    "/fsmonitor--daemon.ipc",
+    "/lfs/tmp/",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OptionsSection {
+    /// Recreate local hardlink groups as hardlinks on the other side
+    #[serde(default = "default_true")]
+    preserve_hardlinks: bool,
+    /// How repository history travels to the NAS
+    #[serde(default)]
+    git_transport: GitTransport,
+    /// Check the repository after each pull
+    #[serde(default)]
+    verify_pull: VerifyMode,
+    /// Keep additional files on the NAS per checked-out branch
+    #[serde(default)]
+    branch_extras: bool,
+}
+
+impl Default for OptionsSection {
+    fn default() -> Self {
+        OptionsSection {
+            preserve_hardlinks: true,
+            git_transport: GitTransport::default(),
+            verify_pull: VerifyMode::default(),
+            branch_extras: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum VerifyMode {
+    #[default]
+    Off,
+    /// Report problems but keep the pulled files
+    Report,
+    /// Restore the files a pull changed if the check fails
+    Rollback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GitTransport {
+    /// Copy `.git` file by file like the rest of the project
+    #[default]
+    Files,
+    /// Push to a bare repository on the NAS and fetch from it
+    Mirror,
+}
+
+/// Bare repository next to the synced files, used by the mirror transport
+const GIT_MIRROR_DIR: &str = ".local-sync-git";
+
+/// Directory on the NAS holding the staged changes and stashes written by
+/// `export_work`. It is kept outside the manifest and replaced on every push.
+const WORK_DIR: &str = ".local-sync-work";
+const STAGED_PATCH: &str = "staged.patch";
+/// `StagedInfo` of the staged changes in `STAGED_PATCH`
+const STAGED_INFO: &str = "staged.json";
+const STASH_BUNDLE: &str = "stash.bundle";
+/// One `<commit> <message>` line per stash, newest first like `git stash list`
+const STASH_LIST: &str = "stash.list";
+/// Temporary refs naming the stashes while they are bundled
+const STASH_TEMP_REFS: &str = "refs/local-sync-stash";
+
+/// Directory on the NAS holding each branch's additional files when
+/// `branch_extras` is set, e.g. `.local-sync-branches/main/.env`.
+const BRANCH_EXTRAS_DIR: &str = ".local-sync-branches";
+
+fn default_true() -> bool {
+    true
+}
+
+fn cmd_list_remote(args: &[String]) -> Result<()> {
+    let Some(root) = args.get(2).map(PathBuf::from) else {
+        bail!("Usage: local-sync list-remote <root>");
+    };
+    if !root.is_dir() {
+        bail!("Not a directory: {}", root.display());
+    }
+
+    let mut projects = Vec::new();
+    find_projects(&root, &mut projects)?;
+    projects.sort();
+
+    if projects.is_empty() {
+        println!("No projects found under {}", root.display());
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:>8} {:>10}  {:<16}  HOST",
+        "PATH", "FILES", "SIZE", "LAST PUSH"
+    );
+    for project in &projects {
+        let rel_path = project.strip_prefix(&root).unwrap_or(project);
+        let display_path = if rel_path.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            rel_path.display().to_string()
+        };
+
+        let manifest = match load_manifest(project) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                println!("{:<40} (unreadable manifest: {})", display_path, err);
+                continue;
+            }
+        };
+
+        let size: u64 = manifest
+            .files
+            .keys()
+            .filter_map(|rel_path| fs::metadata(project.join(rel_path)).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        // Manifests from before push times were recorded only have per-file times
+        let last_push = manifest
+            .pushed_at
+            .or_else(|| manifest.files.values().map(|entry| entry.synced_at).max())
+            .map_or("-".to_string(), |time| {
+                time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string()
+            });
+
+        println!(
+            "{:<40} {:>8} {:>10}  {:<16}  {}",
+            display_path,
+            manifest.files.len(),
+            format_size(size),
+            last_push,
+            manifest.host.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
+
+/// Collects directories holding a manifest, without descending into projects.
+fn find_projects(dir: &Path, projects: &mut Vec<PathBuf>) -> Result<()> {
+    if dir.join(".local-sync-manifest").exists() {
+        projects.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Warning: cannot read {}: {}", dir.display(), err);
+            return Ok(());
+        }
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            find_projects(&entry.path(), projects)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+struct Config {
+    git_root: PathBuf,
+    /// Directory behind `.git` and the one it shares with other worktrees;
+    /// both are `<git_root>/.git` unless `.git` is a file
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+    host: String,
+    target_name: String,
+    nas_path: PathBuf,
+    additional_files: Vec<String>,
+    exclude_patterns: Vec<String>,
+    git_include: Vec<String>,
+    lfs: LfsMode,
+    export_work: bool,
+    preserve_hardlinks: bool,
+    git_transport: GitTransport,
+    verify_pull: VerifyMode,
+    /// NAS directory of the current branch's additional files, if they are
+    /// kept per branch and a branch is checked out
+    extras_prefix: Option<PathBuf>,
+}
+
+/// Per-user settings shared by all projects, read from
+/// `$XDG_CONFIG_HOME/local-sync/config.toml` (`~/.config/...` by default).
+#[derive(Debug, Deserialize)]
+struct UserConfig {
+    /// Directory under which project targets are derived
+    nas_root: Option<String>,
+    /// Target path template for `init` without a path. Supports `{root}`,
+    /// `{user}`, `{host}` and `{repo_name}`.
+    #[serde(default = "default_target_template")]
+    template: String,
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            nas_root: None,
+            template: default_target_template(),
+        }
+    }
+}
+
+fn default_target_template() -> String {
+    "{root}/{user}/{repo_name}".to_string()
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("local-sync").join("config.toml"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+fn load_user_config() -> Result<UserConfig> {
+    let Some(config_path) = user_config_path().filter(|path| path.exists()) else {
+        return Ok(UserConfig::default());
+    };
+
+    let content = fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {}", config_path.display()))
+}
+
+/// Fills in the user config's template to get a target path for `project_root`.
+fn derive_target_path(user_config: &UserConfig, project_root: &Path) -> Result<PathBuf> {
+    let Some(nas_root) = &user_config.nas_root else {
+        bail!(
+            "No path given and no nas_root set in {}\n\
+             Usage: local-sync init <path>",
+            user_config_path().map_or("the user config".to_string(), |path| path.display().to_string())
+        );
+    };
+
+    let mut path = user_config.template.replace("{root}", nas_root);
+    if path.contains("{user}") {
+        path = path.replace("{user}", &user_name()?);
+    }
+    if path.contains("{host}") {
+        path = path.replace("{host}", &host_name());
+    }
+    if path.contains("{repo_name}") {
+        path = path.replace("{repo_name}", &repo_name(project_root));
+    }
+
+    if let Some(start) = path.find('{')
+        && let Some(len) = path[start..].find('}')
+    {
+        bail!("Unknown placeholder in template: {}", &path[start..=start + len]);
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+fn user_name() -> Result<String> {
+    let from_env = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok();
+    let user = from_env.or_else(|| {
+        Command::new("whoami")
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+    });
+
+    user.map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .context("Cannot determine user name for {user}")
+}
+
+/// Names the project after its `origin` remote, falling back to the directory name.
+fn repo_name(project_root: &Path) -> String {
+    let remote = Command::new("git")
+        .current_dir(project_root)
+        .args(["remote", "get-url", "origin"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    let from_remote = remote.and_then(|url| {
+        let url = url.trim().trim_end_matches('/');
+        let name = url.rsplit(['/', ':']).next()?;
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        (!name.is_empty()).then(|| name.to_string())
+    });
+
+    from_remote.unwrap_or_else(|| {
+        project_root
+            .file_name()
+            .map_or("project".to_string(), |name| name.to_string_lossy().to_string())
+    })
+}
+
+fn host_name() -> String {
+    let from_command = Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    from_command
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn get_config(target: Option<&str>) -> Result<Config> {
+    let git_root = find_git_root()?;
+    load_config_from_root(git_root, target)
+}
+
+fn get_config_for_pull(target: Option<&str>) -> Result<Config> {
+    let project_root = find_project_root()?;
+    load_config_from_root(project_root, target)
+}
+
+fn load_config_from_root(root: PathBuf, target: Option<&str>) -> Result<Config> {
+    let config_file = load_config_file(&root)?;
+    let local_file = load_local_config_file(&root)?;
+    let host = local_file.host.clone().unwrap_or_else(host_name);
+    let mut target_config = merge_target_configs(config_file.target_config, local_file.target_config);
+
+    let target_name = match (target, &target_config.default_target) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(name)) => name.clone(),
+        (None, None) if target_config.targets.len() == 1 => {
+            target_config.targets.keys().next().unwrap().clone()
+        }
+        (None, None) => bail!(
+            "No default target configured. Pass --target <name> or run 'local-sync target default <name>'."
+        ),
+    };
+    let Some(target) = target_config.targets.remove(&target_name) else {
+        bail!("No such target: {}", target_name);
+    };
+    let target_path = target.hosts.get(&host).unwrap_or(&target.path);
+    let nas_path = expand_path(target_path)
+        .with_context(|| format!("Invalid path for target {}: {}", target_name, target_path))?;
+
+    // The rollback only covers files copied by the sync, not a mirror fetch
+    if config_file.options.verify_pull == VerifyMode::Rollback
+        && config_file.options.git_transport == GitTransport::Mirror
+    {
+        bail!("verify_pull = \"rollback\" cannot be combined with git_transport = \"mirror\"; use \"report\"");
+    }
+
+    let (git_dir, common_dir) = resolve_git_dirs(&root)?;
+    let extras_prefix = if config_file.options.branch_extras {
+        current_branch(&root).map(|branch| Path::new(BRANCH_EXTRAS_DIR).join(branch))
+    } else {
+        None
+    };
+    Ok(Config {
+        git_root: root,
+        git_dir,
+        common_dir,
+        host,
+        target_name,
+        nas_path,
+        additional_files: config_file.include.paths,
+        exclude_patterns: config_file.exclude.patterns,
+        git_include: config_file.git.include,
+        lfs: config_file.git.lfs,
+        export_work: config_file.git.export_work,
+        preserve_hardlinks: config_file.options.preserve_hardlinks,
+        git_transport: config_file.options.git_transport,
+        verify_pull: config_file.options.verify_pull,
+        extras_prefix,
+    })
+}
+
+/// Short name of the checked-out branch, or `None` on a detached or missing HEAD.
+fn current_branch(root: &Path) -> Option<String> {
+    git_output(root, &["symbolic-ref", "--quiet", "--short", "HEAD"])
+        .ok()
+        .filter(|branch| !branch.is_empty())
+}
+
+/// Strips the current branch's extras directory from a manifest path,
+/// giving the file's path in the working tree.
+fn local_rel<'a>(config: &Config, rel_path: &'a Path) -> &'a Path {
+    config
+        .extras_prefix
+        .as_ref()
+        .and_then(|prefix| rel_path.strip_prefix(prefix).ok())
+        .unwrap_or(rel_path)
+}
+
+/// Checks whether a manifest path holds another branch's additional file,
+/// which no sync touches until that branch is checked out.
+fn is_other_branch_extra(config: &Config, rel_path: &Path) -> bool {
+    rel_path.starts_with(BRANCH_EXTRAS_DIR)
+        && config.extras_prefix.as_ref().is_none_or(|prefix| !rel_path.starts_with(prefix))
+}
+
+/// Branch of the current extras directory, if additional files are kept per branch.
+fn extras_branch(config: &Config) -> Option<String> {
+    let prefix = config.extras_prefix.as_ref()?;
+    let branch = prefix.strip_prefix(BRANCH_EXTRAS_DIR).ok()?;
+    Some(branch.to_string_lossy().into_owned())
+}
+
+/// Refuses to push additional files last synced for another branch into the
+/// current branch's directory while it holds files, since after a checkout the
+/// working tree still has the previous branch's versions.
+fn check_extras_branch(config: &Config, manifest: &Manifest) -> Result<()> {
+    let (Some(branch), Some(prefix)) = (extras_branch(config), &config.extras_prefix) else {
+        return Ok(());
+    };
+    let synced_for = load_local_config_file(&config.git_root)?.extras_branch;
+    if let Some(synced_for) = synced_for
+        && synced_for != branch
+        && manifest.files.keys().any(|rel_path| rel_path.starts_with(prefix))
+    {
+        bail!(
+            "The additional files in the working tree were last synced for branch {}; \
+             run 'local-sync pull' to get those of {} before pushing",
+            synced_for,
+            branch
+        );
+    }
+    Ok(())
+}
+
+/// Remembers which branch the working tree's additional files now belong to.
+fn record_extras_branch(config: &Config) -> Result<()> {
+    let Some(branch) = extras_branch(config) else {
+        return Ok(());
+    };
+    let mut local_file = load_local_config_file(&config.git_root)?;
+    if local_file.extras_branch.as_ref() != Some(&branch) {
+        local_file.extras_branch = Some(branch);
+        save_local_config_file(&config.git_root, &local_file)?;
+    }
+    Ok(())
+}
+
+/// Expands a leading `~` and `$VAR`, `${VAR}` and `${VAR:-default}`
+/// references in a target path.
+fn expand_path(path: &str) -> Result<PathBuf> {
+    let mut expanded = OsString::new();
+    let mut rest = path;
+
+    if let Some(after) = path.strip_prefix('~')
+        && (after.is_empty() || after.starts_with(['/', '\\']))
+    {
+        expanded.push(home_dir().context("Cannot expand ~: home directory is unknown")?);
+        rest = after;
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated ${{ in {}", path))?;
+            let (name, default) = match braced[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&braced[..end], None),
+            };
+            expanded.push(env_var(name, default)?);
+            rest = &braced[end + 1..];
+        } else {
+            let len = after
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(after.len());
+            if len == 0 {
+                expanded.push("$");
+            } else {
+                expanded.push(env_var(&after[..len], None)?);
             }
+            rest = &after[len..];
         }
     }
 
-    for (rel_path, _) in &manifest.files {
-        if !sync_files_set.contains(rel_path) {
-            nas_only += 1;
+    expanded.push(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Looks up a variable for path expansion; empty counts as unset, like `${VAR:-default}` in sh.
+fn env_var(name: &str, default: Option<&str>) -> Result<OsString> {
+    match std::env::var_os(name) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => default
+            .map(OsString::from)
+            .ok_or_else(|| anyhow::anyhow!("Environment variable {} is not set", name)),
+    }
+}
+
+/// Reads `.local-sync.toml`, falling back to a legacy `.local-sync` file.
+fn load_config_file(root: &Path) -> Result<ConfigFile> {
+    let config_path = root.join(CONFIG_FILE);
+    if config_path.exists() {
+        let content = fs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to read {}", config_path.display()))?;
+        let mut config_file: ConfigFile = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", config_path.display()))?;
+        if let Some(target) = config_file.target.take() {
+            let target_config = &mut config_file.target_config;
+            target_config
+                .targets
+                .entry(DEFAULT_TARGET.to_string())
+                .or_insert(target);
+            target_config
+                .default_target
+                .get_or_insert_with(|| DEFAULT_TARGET.to_string());
         }
+        return Ok(config_file);
     }
 
-    println!();
-    println!("Status:");
-    println!("  In sync: {}", in_sync);
-    println!("  Modified: {}", modified);
-    println!("  Local only: {}", local_only);
-    println!("  NAS only: {}", nas_only);
+    let legacy_path = root.join(LEGACY_CONFIG_FILE);
+    if legacy_path.exists() {
+        let content = fs::read_to_string(&legacy_path)
+            .with_context(|| format!("Failed to read {}", legacy_path.display()))?;
+        return parse_legacy_config(&content);
+    }
 
-    if !config.additional_files.is_empty() {
-        println!();
-        println!("Additional files:");
-        for file in &config.additional_files {
-            println!("  +{}", file);
+    bail!(
+        "No {} config file found in: {}\n\
+         Run 'local-sync init <path>' to create one.",
+        CONFIG_FILE,
+        root.display()
+    );
+}
+
+/// Reads the config for modification, which is only supported for the TOML format.
+fn load_editable_config_file(root: &Path) -> Result<ConfigFile> {
+    if !root.join(CONFIG_FILE).exists() {
+        if root.join(LEGACY_CONFIG_FILE).exists() {
+            bail!(
+                "{} uses the legacy format. Run 'local-sync migrate-config' first.",
+                LEGACY_CONFIG_FILE
+            );
+        }
+        bail!("Not initialized. Run 'local-sync init <path>' first.");
+    }
+    load_config_file(root)
+}
+
+fn save_config_file(root: &Path, config_file: &ConfigFile) -> Result<()> {
+    let config_path = root.join(CONFIG_FILE);
+    let content = toml::to_string_pretty(config_file).context("Failed to serialize config")?;
+    fs::write(&config_path, content)
+        .with_context(|| format!("Failed to write {}", config_path.display()))?;
+    Ok(())
+}
+
+/// Markers left in `.git` by a rebase, merge, cherry-pick, revert or bisect.
+const GIT_OPERATION_MARKERS: &[(&str, &str)] = &[
+    ("rebase-merge", "rebase"),
+    ("rebase-apply", "rebase or am"),
+    ("MERGE_HEAD", "merge"),
+    ("CHERRY_PICK_HEAD", "cherry-pick"),
+    ("REVERT_HEAD", "revert"),
+    ("BISECT_LOG", "bisect"),
+];
+
+/// Fails if a git operation is in progress, since syncing would spread a
+/// half-finished repository state. `force` downgrades this to a warning.
+fn check_git_idle(config: &Config, force: bool) -> Result<()> {
+    if !config.git_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut problems = Vec::new();
+    for (marker, operation) in GIT_OPERATION_MARKERS {
+        let path = config.git_dir.join(marker);
+        if path.exists() {
+            problems.push(format!("{} in progress ({} exists)", operation, path.display()));
+        }
+    }
+
+    // Lock files live at the top level (index.lock, HEAD.lock, ...) and under refs
+    let mut candidates = Vec::new();
+    let mut dirs = vec![&config.git_dir];
+    if config.common_dir != config.git_dir && config.common_dir.is_dir() {
+        dirs.push(&config.common_dir);
+    }
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            candidates.push(entry?.path());
+        }
+        if dir.join("refs").is_dir() {
+            candidates.extend(walkdir(&dir.join("refs"))?);
+        }
+    }
+    for path in candidates {
+        if path.extension().is_some_and(|ext| ext == "lock") && path.is_file() {
+            problems.push(format!("lock file {} exists", path.display()));
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for problem in &problems {
+        eprintln!("Git operation in progress: {}", problem);
+    }
+    if force {
+        eprintln!("Warning: continuing because of --force");
+        return Ok(());
+    }
+    bail!("Finish or abort the git operation first, or pass --force to sync anyway");
+}
+
+/// Checks for a `.git` directory, or the `.git` file of a worktree or submodule.
+fn has_git_dir(root: &Path) -> bool {
+    root.join(".git").exists()
+}
+
+/// Finds the git directory behind `root/.git` and the common directory it
+/// shares with other worktrees. In linked worktrees and submodules `.git` is
+/// a file pointing elsewhere.
+fn resolve_git_dirs(root: &Path) -> Result<(PathBuf, PathBuf)> {
+    let dot_git = root.join(".git");
+    if !dot_git.is_file() {
+        return Ok((dot_git.clone(), dot_git));
+    }
+
+    let content = fs::read_to_string(&dot_git)
+        .with_context(|| format!("Failed to read {}", dot_git.display()))?;
+    let Some(target) = content.trim().strip_prefix("gitdir:") else {
+        bail!("Not a gitdir file: {}", dot_git.display());
+    };
+    let git_dir = root.join(target.trim());
+    let git_dir = git_dir.canonicalize().unwrap_or(git_dir);
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => {
+            let common_dir = git_dir.join(common.trim());
+            common_dir.canonicalize().unwrap_or(common_dir)
+        }
+        Err(_) => git_dir.clone(),
+    };
+
+    Ok((git_dir, common_dir))
+}
+
+/// Files a linked worktree keeps for itself rather than in the common directory.
+fn is_per_worktree(git_path: &Path) -> bool {
+    let Some(name) = git_path.iter().next().map(|name| name.to_string_lossy()) else {
+        return false;
+    };
+    name == "HEAD"
+        || name.ends_with("_HEAD")
+        || name.starts_with("BISECT_")
+        || name.starts_with("MERGE_")
+        || ["index", "COMMIT_EDITMSG", "rebase-merge", "rebase-apply", "sequencer", "config.worktree", LOCAL_CONFIG_FILE]
+            .contains(&name.as_ref())
+        || ["logs/HEAD", "refs/bisect", "refs/worktree", "refs/rewritten"]
+            .iter()
+            .any(|prefix| git_path.starts_with(prefix))
+}
+
+/// Maps a project-relative path to the local file, sending `.git/...` paths
+/// to the real git directory when `.git` is a file.
+fn local_file_path(config: &Config, rel_path: &Path) -> Result<PathBuf> {
+    let rel_path = local_rel(config, rel_path);
+    match rel_path.strip_prefix(".git") {
+        Ok(git_path) if config.git_dir != config.git_root.join(".git") => {
+            let base = if is_per_worktree(git_path) {
+                &config.git_dir
+            } else {
+                &config.common_dir
+            };
+            safe_join(base, git_path)
+        }
+        _ => safe_join(&config.git_root, rel_path),
+    }
+}
+
+/// Lists the files of the git directory as `.git/...` paths. A linked
+/// worktree contributes its own files on top of the common directory's.
+fn walk_git_dir(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let linked = config.git_dir != config.common_dir;
+
+    if config.common_dir.is_dir() {
+        for path in walkdir(&config.common_dir)? {
+            let git_path = path.strip_prefix(&config.common_dir)?;
+            if !linked || !is_per_worktree(git_path) {
+                files.push(Path::new(".git").join(git_path));
+            }
+        }
+    }
+    if linked && config.git_dir.is_dir() {
+        for path in walkdir(&config.git_dir)? {
+            let git_path = path.strip_prefix(&config.git_dir)?;
+            if is_per_worktree(git_path) {
+                files.push(Path::new(".git").join(git_path));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn local_config_path(root: &Path) -> PathBuf {
+    let git_dir = resolve_git_dirs(root).map_or_else(|_| root.join(".git"), |(git_dir, _)| git_dir);
+    git_dir.join(LOCAL_CONFIG_FILE)
+}
+
+fn load_local_config_file(root: &Path) -> Result<LocalConfigFile> {
+    let config_path = local_config_path(root);
+    if !config_path.exists() {
+        return Ok(LocalConfigFile::default());
+    }
+
+    let content = fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {}", config_path.display()))
+}
+
+fn save_local_config_file(root: &Path, local_file: &LocalConfigFile) -> Result<()> {
+    let config_path = local_config_path(root);
+    let content = toml::to_string_pretty(local_file).context("Failed to serialize config")?;
+    fs::write(&config_path, content)
+        .with_context(|| format!("Failed to write {}", config_path.display()))?;
+    Ok(())
+}
+
+/// Combines shared and private targets; private ones win on name clashes.
+fn merge_target_configs(shared: TargetsConfig, local: TargetsConfig) -> TargetsConfig {
+    let mut merged = shared;
+    merged.targets.extend(local.targets);
+    if local.default_target.is_some() {
+        merged.default_target = local.default_target;
+    }
+    merged
+}
+
+fn parse_legacy_config(content: &str) -> Result<ConfigFile> {
+    let mut lines = content.lines();
+    let nas_path_str = lines
+        .next()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| anyhow::anyhow!(".local-sync file is empty. It should contain the NAS target path."))?;
+
+    let additional_files: Vec<String> = lines
+        .filter_map(|line| {
+            line.trim().strip_prefix('+').map(|path| include_entry(OsStr::new(path)))
+        })
+        .collect();
+
+    Ok(ConfigFile {
+        target_config: TargetsConfig {
+            default_target: Some(DEFAULT_TARGET.to_string()),
+            targets: BTreeMap::from([(
+                DEFAULT_TARGET.to_string(),
+                TargetSection {
+                    path: nas_path_str.to_string(),
+                    hosts: BTreeMap::new(),
+                },
+            )]),
+        },
+        include: IncludeSection {
+            paths: additional_files,
+        },
+        ..ConfigFile::default()
+    })
+}
+
+/// Groups files whose paths would collide on a case-insensitive,
+/// Unicode-normalizing filesystem, with each other or with files already on
+/// the NAS under another spelling.
+fn find_path_collisions<'a>(
+    files: &'a [PathBuf],
+    nas_files: impl IntoIterator<Item = &'a PathBuf>,
+) -> Vec<Vec<&'a PathBuf>> {
+    let mut groups: HashMap<String, BTreeSet<&PathBuf>> = HashMap::new();
+    for path in files {
+        groups.entry(collision_key(path)).or_default().insert(path);
+    }
+    for path in nas_files {
+        if let Some(group) = groups.get_mut(&collision_key(path)) {
+            group.insert(path);
+        }
+    }
+
+    let mut collisions: Vec<Vec<_>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|group| group.into_iter().collect())
+        .collect();
+    collisions.sort();
+    collisions
+}
+
+/// The form under which a case-insensitive, normalizing filesystem sees `path`.
+fn collision_key(path: &Path) -> String {
+    encode_path(path).nfc().collect::<String>().to_lowercase()
+}
+
+struct SyncFiles {
+    files: Vec<PathBuf>,
+    /// Maps each hardlinked file to the first file of its link group
+    hardlinks: HashMap<PathBuf, PathBuf>,
+}
+
+fn get_sync_files(config: &Config) -> Result<SyncFiles> {
+    let git_policy = build_git_policy(config)?;
+    let mut files = Vec::new();
+    collect_git_files(&config.git_root, Path::new(""), &git_policy, &mut files)?;
+    let mut files_set: HashSet<_> = files.iter().cloned().collect();
+
+    // Always include git config files if they exist
+    for git_file in [".gitignore", ".gitattributes"].map(PathBuf::from) {
+        if config.git_root.join(&git_file).exists() && !files_set.contains(&git_file) {
+            files_set.insert(git_file.clone());
+            files.push(git_file);
+        }
+    }
+
+    // Always include the git directory if it exists, except for volatile
+    // files and our private config
+    let local_config = Path::new(".git").join(LOCAL_CONFIG_FILE);
+    if config.git_transport == GitTransport::Files {
+        for rel_path in walk_git_dir(config)? {
+            if rel_path != local_config
+                && !is_git_skipped(&git_policy, &rel_path)
+                && !files_set.contains(&rel_path)
+            {
+                files_set.insert(rel_path.clone());
+                files.push(rel_path);
+            }
+        }
+    }
+
+    // Add additional files/directories that aren't already in git
+    let mut extras = HashSet::new();
+    let (patterns, paths): (Vec<_>, Vec<_>) = config
+        .additional_files
+        .iter()
+        .cloned()
+        .partition(|entry| is_pattern(entry));
+    for entry in &paths {
+        let entry_path = decode_path(entry).with_context(|| format!("Invalid path in [include]: {}", entry))?;
+        let full_path = safe_join(&config.git_root, &entry_path)?;
+
+        if full_path.is_dir() {
+            // Expand directory to all files within
+            for file_path in walkdir(&full_path)? {
+                if let Ok(rel_path) = file_path.strip_prefix(&config.git_root)
+                    && !files_set.contains(rel_path)
+                {
+                    files_set.insert(rel_path.to_path_buf());
+                    extras.insert(rel_path.to_path_buf());
+                    files.push(rel_path.to_path_buf());
+                }
+            }
+        } else if !files_set.contains(&entry_path) {
+            files_set.insert(entry_path.clone());
+            extras.insert(entry_path.clone());
+            files.push(entry_path);
+        }
+    }
+
+    // Add gitignored files matching additional patterns
+    if !patterns.is_empty() {
+        let includes = build_includes(&config.git_root, &patterns)?;
+        for rel_path in get_ignored_files(&config.git_root)? {
+            if is_matched(&includes, &rel_path) && !files_set.contains(&rel_path) {
+                files_set.insert(rel_path.clone());
+                extras.insert(rel_path.clone());
+                files.push(rel_path);
+            }
+        }
+    }
+
+    // Dangling symlinks and the like are skipped with a warning; any path left
+    // that leads outside the project is an error
+    let excludes = build_excludes(config)?;
+    files.retain(|rel_path| {
+        !is_matched(&excludes, rel_path)
+            && match local_file_path(config, rel_path) {
+                Ok(path) => !skip_special_file(&path),
+                Err(_) => !skip_outside_symlink(config, rel_path),
+            }
+    });
+    for rel_path in &files {
+        local_file_path(config, rel_path)?;
+    }
+
+    // LFS objects the server already has are left out if so configured
+    if config.lfs == LfsMode::SkipRemote && uses_lfs(config) {
+        let remote = lfs_remote_objects(&config.git_root)?;
+        files.retain(|rel_path| lfs_object_id(rel_path).is_none_or(|oid| !remote.contains(&oid)));
+    }
+
+    // Additional files live under the branch's directory on the NAS if so configured
+    if let Some(prefix) = &config.extras_prefix {
+        for rel_path in &mut files {
+            if extras.contains(rel_path) {
+                *rel_path = prefix.join(&*rel_path);
+            }
+        }
+    }
+
+    let hardlinks = if config.preserve_hardlinks {
+        find_hardlink_groups(config, &files)
+    } else {
+        HashMap::new()
+    };
+
+    Ok(SyncFiles { files, hardlinks })
+}
+
+/// Builds the exclude matcher from the config's patterns and `.localsyncignore`.
+fn build_excludes(config: &Config) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(&config.git_root);
+
+    let ignore_path = config.git_root.join(IGNORE_FILE);
+    if ignore_path.exists()
+        && let Some(err) = builder.add(&ignore_path)
+    {
+        return Err(err).with_context(|| format!("Failed to read {}", ignore_path.display()));
+    }
+
+    for pattern in &config.exclude_patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid exclude pattern: {}", pattern))?;
+    }
+
+    builder.build().context("Failed to build exclude patterns")
+}
+
+/// Decides which files inside git directories stay out of the file sync.
+struct GitPolicy {
+    matcher: Gitignore,
+    /// Submodule git directories inside `.git`, e.g. `modules/lib`
+    module_dirs: Vec<PathBuf>,
+}
+
+/// Builds the matcher for `.git` files kept out of the file sync: the volatile
+/// ones minus the configured overrides, or everything in mirror mode.
+fn build_git_policy(config: &Config) -> Result<GitPolicy> {
+    let normalize = |pattern: &str| pattern.trim_start_matches('/').to_string();
+    let overrides: HashSet<_> = config.git_include.iter().map(|p| normalize(p)).collect();
+
+    for name in &overrides {
+        if !GIT_VOLATILE_PATTERNS.iter().any(|pattern| normalize(pattern) == *name) {
+            bail!(
+                "Unknown [git] include entry: {}\nValid entries: {}",
+                name,
+                GIT_VOLATILE_PATTERNS.join(", ")
+            );
+        }
+    }
+
+    let mut builder = GitignoreBuilder::new(config.git_root.join(".git"));
+    if config.git_transport == GitTransport::Mirror {
+        builder.add_line(None, "*")?;
+    }
+    for pattern in GIT_VOLATILE_PATTERNS {
+        if !overrides.contains(&normalize(pattern)) {
+            builder.add_line(None, pattern)?;
         }
     }
+    if config.export_work {
+        if overrides.contains("index") {
+            bail!("[git] include = [\"/index\"] cannot be combined with export_work");
+        }
+        builder.add_line(None, "/refs/stash")?;
+    }
+
+    let mut module_dirs = Vec::new();
+    for modules in [config.common_dir.join("modules"), config.nas_path.join(".git/modules")] {
+        find_module_dirs(&modules, Path::new("modules"), &mut module_dirs);
+    }
+
+    Ok(GitPolicy {
+        matcher: builder.build().context("Failed to build git sync policy")?,
+        module_dirs,
+    })
+}
+
+/// Collects submodule git directories (those holding a `HEAD`) below `dir`.
+fn find_module_dirs(dir: &Path, prefix: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let rel_path = prefix.join(entry.file_name());
+        if path.join("HEAD").is_file() {
+            find_module_dirs(&path.join("modules"), &rel_path.join("modules"), found);
+            if !found.contains(&rel_path) {
+                found.push(rel_path);
+            }
+        } else {
+            // Submodule names may contain slashes
+            find_module_dirs(&path, &rel_path, found);
+        }
+    }
+}
+
+/// Checks whether a project-relative path inside a git directory is left out
+/// of the file sync. Paths are matched relative to the innermost git
+/// directory: `.git`, a submodule's embedded `.git` or `.git/modules/<name>`.
+fn is_git_skipped(policy: &GitPolicy, rel_path: &Path) -> bool {
+    let parts: Vec<_> = rel_path.iter().collect();
+    let Some(pos) = parts.iter().rposition(|part| *part == ".git") else {
+        return false;
+    };
+    let git_path: PathBuf = parts[pos + 1..].iter().collect();
+    if git_path.as_os_str().is_empty() {
+        return false;
+    }
 
-    Ok(())
+    let module = policy
+        .module_dirs
+        .iter()
+        .filter(|dir| git_path.starts_with(dir))
+        .max_by_key(|dir| dir.components().count());
+    match module {
+        Some(dir) => is_matched(&policy.matcher, git_path.strip_prefix(dir).unwrap()),
+        None => is_matched(&policy.matcher, &git_path),
+    }
 }
 
-struct Config {
-    git_root: PathBuf,
-    nas_path: PathBuf,
-    additional_files: Vec<String>,
-}
+/// Builds a matcher for additional include patterns, which use the same
+/// gitignore-style syntax as excludes.
+fn build_includes(root: &Path, patterns: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid include pattern: {}", pattern))?;
+    }
 
-fn get_config() -> Result<Config> {
-    let git_root = find_git_root()?;
-    load_config_from_root(git_root)
+    builder.build().context("Failed to build include patterns")
 }
 
-fn get_config_for_pull() -> Result<Config> {
-    let project_root = find_project_root()?;
-    load_config_from_root(project_root)
+/// Checks whether `rel_path` or any of its parent directories matches.
+fn is_matched(matcher: &Gitignore, rel_path: &Path) -> bool {
+    matcher.matched_path_or_any_parents(rel_path, false).is_ignore()
 }
 
-fn load_config_from_root(root: PathBuf) -> Result<Config> {
-    let config_path = root.join(".local-sync");
+/// Tells glob patterns apart from literal paths in the include list.
+fn is_pattern(entry: &str) -> bool {
+    entry.contains(['*', '?', '['])
+}
 
-    if !config_path.exists() {
-        bail!(
-            "No .local-sync config file found in: {}\n\
-             Create a .local-sync file containing the NAS target path.",
-            root.display()
-        );
-    }
+/// Groups files sharing an inode, mapping every file after the first in each
+/// group to that first file.
+#[cfg(unix)]
+fn find_hardlink_groups(config: &Config, files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    use std::os::unix::fs::MetadataExt;
 
-    let content = fs::read_to_string(&config_path)
-        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let mut leaders: HashMap<(u64, u64), &PathBuf> = HashMap::new();
+    let mut hardlinks = HashMap::new();
 
-    let mut lines = content.lines();
-    let nas_path_str = lines
-        .next()
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .ok_or_else(|| anyhow::anyhow!(".local-sync file is empty. It should contain the NAS target path."))?;
+    for rel_path in files {
+        let Some(metadata) = local_file_path(config, rel_path)
+            .ok()
+            .and_then(|path| fs::metadata(path).ok())
+        else {
+            continue;
+        };
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            continue;
+        }
 
-    let nas_path = PathBuf::from(nas_path_str);
+        let leader = *leaders
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert(rel_path);
+        if leader != rel_path {
+            hardlinks.insert(rel_path.clone(), leader.clone());
+        }
+    }
 
-    let additional_files: Vec<String> = lines
-        .filter_map(|line| {
-            let trimmed = line.trim();
-            if trimmed.starts_with('+') {
-                Some(trimmed[1..].to_string())
-            } else {
-                None
-            }
-        })
-        .collect();
+    hardlinks
+}
 
-    Ok(Config {
-        git_root: root,
-        nas_path,
-        additional_files,
-    })
+#[cfg(not(unix))]
+fn find_hardlink_groups(_config: &Config, _files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    HashMap::new()
 }
 
-fn get_sync_files(config: &Config) -> Result<Vec<String>> {
-    let mut files = get_git_files(&config.git_root)?;
-    let mut files_set: HashSet<_> = files.iter().cloned().collect();
+/// Checks whether `path` is not (yet) a hardlink of `leader`.
+#[cfg(unix)]
+fn needs_relink(leader: &Path, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-    // Always include git config files if they exist
-    for git_file in &[".gitignore", ".gitattributes"] {
-        if config.git_root.join(git_file).exists() && !files_set.contains(*git_file) {
-            files_set.insert(git_file.to_string());
-            files.push(git_file.to_string());
-        }
-    }
-
-    // Always include .git directory if it exists
-    let git_dir = config.git_root.join(".git");
-    if git_dir.exists() && git_dir.is_dir() {
-        for file_path in walkdir(&git_dir)? {
-            if let Ok(rel_path) = file_path.strip_prefix(&config.git_root) {
-                let rel_str = rel_path.to_string_lossy().to_string();
-                if !files_set.contains(&rel_str) {
-                    files_set.insert(rel_str.clone());
-                    files.push(rel_str);
-                }
-            }
-        }
+    match (fs::metadata(leader), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() != b.dev() || a.ino() != b.ino(),
+        _ => true,
     }
+}
 
-    // Add additional files/directories that aren't already in git
-    for entry in &config.additional_files {
-        let full_path = config.git_root.join(entry);
+#[cfg(not(unix))]
+fn needs_relink(_leader: &Path, _path: &Path) -> bool {
+    false
+}
 
-        if full_path.is_dir() {
-            // Expand directory to all files within
-            for file_path in walkdir(&full_path)? {
-                if let Ok(rel_path) = file_path.strip_prefix(&config.git_root) {
-                    let rel_str = rel_path.to_string_lossy().to_string();
-                    if !files_set.contains(&rel_str) {
-                        files_set.insert(rel_str.clone());
-                        files.push(rel_str);
-                    }
-                }
-            }
-        } else if !files_set.contains(entry) {
-            files_set.insert(entry.clone());
-            files.push(entry.clone());
-        }
+/// Checks whether both paths lead to the same file, e.g. two spellings of a
+/// name on a case-insensitive filesystem.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
     }
+}
 
-    Ok(files)
+#[cfg(not(unix))]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
 }
 
 fn find_git_root() -> Result<PathBuf> {
@@ -650,30 +3532,65 @@ fn find_git_root() -> Result<PathBuf> {
         bail!("Not in a git repository");
     }
 
-    let path = String::from_utf8(output.stdout)
-        .context("Invalid UTF-8 in git output")?
-        .trim()
-        .to_string();
+    let mut path = output.stdout;
+    while path.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        path.pop();
+    }
 
-    Ok(PathBuf::from(path))
+    path_from_bytes(path)
 }
 
 fn find_project_root() -> Result<PathBuf> {
     let mut current = std::env::current_dir()?;
     loop {
-        if current.join(".local-sync").exists() {
+        if current.join(CONFIG_FILE).exists() || current.join(LEGACY_CONFIG_FILE).exists() {
             return Ok(current);
         }
         if !current.pop() {
-            bail!("No .local-sync file found in current directory or any parent directory");
+            bail!("No {} file found in current directory or any parent directory", CONFIG_FILE);
+        }
+    }
+}
+
+/// Lists the files git knows about under `root`, descending into checked-out
+/// submodules. A submodule's `.git` file, or its embedded `.git` directory
+/// minus volatile files, is listed too.
+fn collect_git_files(root: &Path, prefix: &Path, policy: &GitPolicy, files: &mut Vec<PathBuf>) -> Result<()> {
+    let submodules = get_gitlinks(root)?;
+
+    for rel_path in get_git_files(root)? {
+        if !submodules.contains(&rel_path) {
+            files.push(prefix.join(rel_path));
+            continue;
+        }
+
+        let sub_root = root.join(&rel_path);
+        let sub_prefix = prefix.join(&rel_path);
+        let dot_git = sub_root.join(".git");
+        if dot_git.is_dir() {
+            for path in walkdir(&dot_git)? {
+                let key = sub_prefix.join(path.strip_prefix(&sub_root)?);
+                if !is_git_skipped(policy, &key) {
+                    files.push(key);
+                }
+            }
+        } else if dot_git.is_file() {
+            files.push(sub_prefix.join(".git"));
+        } else {
+            // Not checked out here; there is nothing to sync
+            continue;
         }
+        collect_git_files(&sub_root, &sub_prefix, policy, files)?;
     }
+
+    Ok(())
 }
 
-fn get_git_files(git_root: &Path) -> Result<Vec<String>> {
+/// Lists the submodule paths of the index (entries with mode 160000).
+fn get_gitlinks(git_root: &Path) -> Result<HashSet<PathBuf>> {
     let output = Command::new("git")
         .current_dir(git_root)
-        .args(["ls-files", "--cached", "--others", "--exclude-standard"])
+        .args(["ls-files", "-z", "--stage"])
         .output()
         .context("Failed to run git ls-files")?;
 
@@ -681,22 +3598,88 @@ fn get_git_files(git_root: &Path) -> Result<Vec<String>> {
         bail!("git ls-files failed");
     }
 
-    let files: Vec<String> = String::from_utf8(output.stdout)
-        .context("Invalid UTF-8 in git output")?
-        .lines()
-        .filter(|s| !s.is_empty())
-        .map(|s| s.to_string())
-        .collect();
+    output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|record| record.starts_with(b"160000 "))
+        .filter_map(|record| {
+            let tab = record.iter().position(|&b| b == b'\t')?;
+            Some(path_from_bytes(record[tab + 1..].to_vec()))
+        })
+        .collect()
+}
 
-    Ok(files)
+fn get_git_files(git_root: &Path) -> Result<Vec<PathBuf>> {
+    git_ls_files(git_root, &["--cached", "--others", "--exclude-standard"])
+}
+
+/// Lists untracked files that git ignores, the candidates for include patterns.
+fn get_ignored_files(git_root: &Path) -> Result<Vec<PathBuf>> {
+    git_ls_files(git_root, &["--others", "--ignored", "--exclude-standard"])
+}
+
+/// Runs a git command and returns its trimmed standard output.
+fn git_output(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs a git command built by the caller, e.g. one with path arguments that
+/// `git_output` can't take, and fails with git's error output.
+fn run_git(command: &mut Command) -> Result<()> {
+    let output = command.output().context("Failed to run git")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+fn git_ls_files(git_root: &Path, args: &[&str]) -> Result<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .current_dir(git_root)
+        .args(["ls-files", "-z"])
+        .args(args)
+        .output()
+        .context("Failed to run git ls-files")?;
+
+    if !output.status.success() {
+        bail!("git ls-files failed");
+    }
+
+    output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| path_from_bytes(s.to_vec()))
+        .collect()
 }
 
 fn hash_file(path: &Path) -> Result<String> {
+    if is_special_file(path) {
+        bail!("Not a regular file: {}", path.display());
+    }
     let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hash_bytes(&content))
+}
+
+fn hash_bytes(content: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(&content);
+    hasher.update(content);
     let result = hasher.finalize();
-    Ok(format!("sha256:{:x}", result))
+    format!("sha256:{:x}", result)
 }
 
 fn load_manifest(nas_path: &Path) -> Result<Manifest> {
@@ -708,8 +3691,14 @@ fn load_manifest(nas_path: &Path) -> Result<Manifest> {
     let content = fs::read_to_string(&manifest_path)
         .with_context(|| format!("Failed to read manifest: {}", manifest_path.display()))?;
 
-    let manifest: Manifest =
+    let mut value: serde_json::Value =
         serde_json::from_str(&content).with_context(|| "Failed to parse manifest")?;
+    if value.get("version").is_none() {
+        upgrade_legacy_manifest(&mut value);
+    }
+
+    let manifest: Manifest =
+        serde_json::from_value(value).with_context(|| "Failed to parse manifest")?;
 
     Ok(manifest)
 }
@@ -722,6 +3711,230 @@ fn save_manifest(nas_path: &Path, manifest: &Manifest) -> Result<()> {
     Ok(())
 }
 
+/// Copies `source` over `dest`, first unlinking `dest` if it shares its inode
+/// with other files so they aren't modified along with it.
+fn copy_file(source: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Never write through a link, be it a hardlink or a (dangling) symlink
+    if is_multiply_linked(dest) || dest.is_symlink() {
+        fs::remove_file(dest)?;
+    }
+    fs::copy(source, dest)?;
+    Ok(())
+}
+
+/// Recreates `dest` as a hardlink of `leader`, falling back to copying
+/// `source` when the filesystem doesn't support hardlinks. Returns whether a
+/// link was created.
+fn link_or_copy(leader: &Path, source: &Path, dest: &Path) -> Result<bool> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if dest.exists() || dest.is_symlink() {
+        fs::remove_file(dest)?;
+    }
+    if fs::hard_link(leader, dest).is_ok() {
+        return Ok(true);
+    }
+    fs::copy(source, dest)?;
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn is_multiply_linked(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.nlink() > 1)
+}
+
+#[cfg(not(unix))]
+fn is_multiply_linked(_path: &Path) -> bool {
+    false
+}
+
+/// Joins a relative path onto `root`, failing loudly if it could resolve
+/// outside of it: the path must consist only of normal components, and any
+/// symlink along it, the final component included, must point back inside
+/// `root`. Only a dangling final symlink is let through, for the caller to
+/// skip (see `skip_special_file`) or replace (see `copy_file`).
+fn safe_join(root: &Path, rel_path: &Path) -> Result<PathBuf> {
+    let is_relative = rel_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if rel_path.as_os_str().is_empty() || !is_relative {
+        bail!(
+            "Refusing to access path outside of {}: {}",
+            root.display(),
+            rel_path.display()
+        );
+    }
+
+    let mut current = root.to_path_buf();
+    let mut components = rel_path.components().peekable();
+    while let Some(component) = components.next() {
+        current.push(component);
+        let Ok(metadata) = current.symlink_metadata() else {
+            break;
+        };
+        if !metadata.file_type().is_symlink() {
+            continue;
+        }
+
+        let canonical_root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", root.display()))?;
+        let escapes = match current.canonicalize() {
+            Ok(target) => !target.starts_with(&canonical_root),
+            Err(_) => components.peek().is_some(),
+        };
+        if escapes {
+            bail!(
+                "Refusing to follow symlink outside of {}: {}",
+                root.display(),
+                current.display()
+            );
+        }
+    }
+
+    Ok(root.join(rel_path))
+}
+
+/// Escapes the `%` characters of a pre-version-1 manifest's paths so they
+/// decode back to the literal paths they were written as.
+fn upgrade_legacy_manifest(value: &mut serde_json::Value) {
+    let Some(files) = value.get_mut("files").and_then(|f| f.as_object_mut()) else {
+        return;
+    };
+
+    *files = std::mem::take(files)
+        .into_iter()
+        .map(|(path, mut entry)| {
+            if let Some(hardlink) = entry.get_mut("hardlink")
+                && let Some(leader) = hardlink.as_str()
+            {
+                *hardlink = leader.replace('%', "%25").into();
+            }
+            (path.replace('%', "%25"), entry)
+        })
+        .collect();
+}
+
+/// Encodes a relative path for the manifest in its canonical form: components
+/// are joined with `/` regardless of platform, and `%` and any bytes that
+/// aren't valid UTF-8 are percent-escaped so every filename round-trips exactly.
+fn encode_path(path: &Path) -> String {
+    let components: Vec<String> = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| encode_component(component.as_os_str().as_encoded_bytes()))
+        .collect();
+    components.join("/")
+}
+
+fn encode_component(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.utf8_chunks() {
+        encoded.push_str(&chunk.valid().replace('%', "%25"));
+        for byte in chunk.invalid() {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Decodes a canonical manifest path into a native relative path, rejecting
+/// empty, `.`, `..` and absolute or drive-prefixed components.
+fn decode_path(encoded: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in encoded.split('/') {
+        let component = path_from_bytes(decode_component(component, encoded)?)?;
+        let mut parts = component.components();
+        match (parts.next(), parts.next()) {
+            (Some(Component::Normal(_)), None) => path.push(component),
+            _ => bail!("Invalid path in manifest: {}", encoded),
+        }
+    }
+    Ok(path)
+}
+
+fn decode_component(component: &str, encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut rest = component.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let escaped = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid escape in manifest path: {}", encoded))?;
+            bytes.push(escaped);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    let path = String::from_utf8(bytes).context("Non-UTF-8 paths are not supported on this platform")?;
+    Ok(PathBuf::from(OsString::from(path)))
+}
+
+fn serialize_path_map<S: Serializer>(
+    files: &HashMap<PathBuf, FileEntry>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(files.iter().map(|(path, entry)| (encode_path(path), entry)))
+}
+
+fn deserialize_path_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<PathBuf, FileEntry>, D::Error> {
+    HashMap::<String, FileEntry>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(path, entry)| Ok((decode_path(&path).map_err(serde::de::Error::custom)?, entry)))
+        .collect()
+}
+
+fn serialize_opt_path<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+    path.as_deref().map(encode_path).serialize(serializer)
+}
+
+fn deserialize_opt_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|path| decode_path(&path).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Reads the value of a `--name value` or `--name=value` command line option.
+fn option_value(args: &[String], name: &str) -> Result<Option<String>> {
+    let prefix = format!("{}=", name);
+    for (i, arg) in args.iter().enumerate() {
+        if arg == name {
+            let value = args
+                .get(i + 1)
+                .ok_or_else(|| anyhow::anyhow!("Missing value for {}", name))?;
+            return Ok(Some(value.clone()));
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Ok(Some(value.to_string()));
+        }
+    }
+    Ok(None)
+}
+
 fn prompt_continue(message: &str) -> Result<bool> {
     eprint!("{} [Y/n] ", message);
     io::stderr().flush()?;
@@ -762,10 +3975,12 @@ fn walkdir_recursive(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
         return Ok(());
     }
 
+    // Symlinks are listed rather than followed, so a link to a directory never
+    // leads the walk outside or into a loop; `skip_special_file` drops it
     for entry in fs::read_dir(path)? {
         let entry = entry?;
         let entry_path = entry.path();
-        if entry_path.is_dir() {
+        if entry.file_type()?.is_dir() {
             walkdir_recursive(&entry_path, files)?;
         } else {
             files.push(entry_path);
@@ -774,3 +3989,314 @@ fn walkdir_recursive(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
 
     Ok(())
 }
+
+/// Checks whether `path` exists but is not a regular file, e.g. a FIFO,
+/// socket, device node, dangling symlink or symlink to a directory. These must
+/// never be read, as opening a FIFO blocks forever.
+fn is_special_file(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => !metadata.is_file(),
+        Err(_) => path.symlink_metadata().is_ok(),
+    }
+}
+
+/// Warns about and reports a symlink out of the project, which `safe_join`
+/// refuses. Like special files it is left out of the sync rather than failing it.
+fn skip_outside_symlink(config: &Config, rel_path: &Path) -> bool {
+    let (Some(parent), Some(name)) = (rel_path.parent(), rel_path.file_name()) else {
+        return false;
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Ok(config.git_root.clone())
+    } else {
+        local_file_path(config, parent)
+    };
+    let Ok(path) = parent.map(|parent| parent.join(name)) else {
+        return false;
+    };
+    let outside = path.is_symlink();
+    if outside {
+        eprintln!("Warning: skipping symlink outside of the project: {}", path.display());
+    }
+    outside
+}
+
+/// Warns about and reports special files that should be left out of the sync.
+fn skip_special_file(path: &Path) -> bool {
+    let special = is_special_file(path);
+    if special {
+        eprintln!("Warning: skipping special file: {}", path.display());
+    }
+    special
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_path_round_trips() {
+        for path in ["src/main.rs", ".git/refs/heads/main", "dir with spaces/ü.txt"] {
+            let encoded = encode_path(Path::new(path));
+            assert_eq!(encoded, path);
+            assert_eq!(decode_path(&encoded).unwrap(), PathBuf::from(path));
+        }
+    }
+
+    #[test]
+    fn encode_path_escapes_percent() {
+        let path = Path::new("100%/a%25b");
+        let encoded = encode_path(path);
+        assert_eq!(encoded, "100%25/a%2525b");
+        assert_eq!(decode_path(&encoded).unwrap(), path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_path_round_trips_invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"dir/caf\xe9 %.txt"));
+        let encoded = encode_path(path);
+        assert_eq!(encoded, "dir/caf%E9 %25.txt");
+        assert_eq!(decode_path(&encoded).unwrap(), path);
+    }
+
+    #[test]
+    fn decode_path_rejects_escaping_paths() {
+        for encoded in [
+            "",
+            "../etc/passwd",
+            "a/../b",
+            "/etc/passwd",
+            "./a",
+            "a//b",
+            "a/",
+            "%2E%2E/a",
+            "a%2Fb",
+            "%2Fetc",
+        ] {
+            assert!(decode_path(encoded).is_err(), "accepted {:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn decode_path_rejects_bad_escapes() {
+        for encoded in ["a%", "a%2", "a%G1", "a%%"] {
+            assert!(decode_path(encoded).is_err(), "accepted {:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn safe_join_rejects_escaping_paths() {
+        let root = Path::new("/nonexistent/root");
+        assert_eq!(safe_join(root, Path::new("a/b")).unwrap(), root.join("a/b"));
+        for rel_path in ["", "../a", "a/../../b", "/etc/passwd", "./a"] {
+            assert!(safe_join(root, Path::new(rel_path)).is_err(), "accepted {:?}", rel_path);
+        }
+    }
+
+    #[test]
+    fn safe_join_checks_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("safe-join-symlinks");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        symlink("../secret", root.join("out.txt")).unwrap();
+        symlink("..", root.join("up")).unwrap();
+        symlink("sub", root.join("in")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+
+        assert!(safe_join(&root, Path::new("out.txt")).is_err());
+        assert!(safe_join(&root, Path::new("up/secret")).is_err());
+        assert!(safe_join(&root, Path::new("dangling/a")).is_err());
+        assert_eq!(safe_join(&root, Path::new("in/a")).unwrap(), root.join("in/a"));
+        // A dangling final symlink is left for the caller to skip or replace
+        assert_eq!(safe_join(&root, Path::new("dangling")).unwrap(), root.join("dangling"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_path_collisions_includes_nas_paths() {
+        let files = ["README.md", "readme.md", "caf\u{e9}.txt", "notes.txt"].map(PathBuf::from);
+        let nas_files = ["cafe\u{301}.txt", "Notes.txt", "other.txt", "notes.txt"].map(PathBuf::from);
+        let collisions = find_path_collisions(&files, &nas_files);
+        let names: Vec<Vec<_>> = collisions
+            .iter()
+            .map(|group| group.iter().map(|path| path.to_str().unwrap()).collect())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                vec!["Notes.txt", "notes.txt"],
+                vec!["README.md", "readme.md"],
+                vec!["cafe\u{301}.txt", "caf\u{e9}.txt"],
+            ]
+        );
+    }
+
+    /// Creates an empty directory for a test under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("local-sync-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_config(root: &Path) -> Config {
+        Config {
+            git_root: root.to_path_buf(),
+            git_dir: root.join(".git"),
+            common_dir: root.join(".git"),
+            host: "here".to_string(),
+            target_name: DEFAULT_TARGET.to_string(),
+            nas_path: root.to_path_buf(),
+            additional_files: Vec::new(),
+            exclude_patterns: Vec::new(),
+            git_include: Vec::new(),
+            lfs: LfsMode::Sync,
+            export_work: false,
+            preserve_hardlinks: true,
+            git_transport: GitTransport::Files,
+            verify_pull: VerifyMode::Off,
+            extras_prefix: None,
+        }
+    }
+
+    /// Commits the empty tree on top of `parent` and returns the commit's id.
+    fn commit(root: &Path, parent: Option<&str>, message: &str) -> String {
+        let tree = git_output(root, &["mktree"]).unwrap();
+        let mut args = vec!["-c", "user.name=t", "-c", "user.email=t@t", "commit-tree", &tree, "-m", message];
+        if let Some(parent) = parent {
+            args.extend(["-p", parent]);
+        }
+        git_output(root, &args).unwrap()
+    }
+
+    fn write_packed_refs(path: &Path, traits: &str, refs: &[(&str, &str)]) {
+        let mut content = format!("# pack-refs with: {} \n", traits);
+        for (oid, name) in refs {
+            content.push_str(&format!("{} {}\n", oid, name));
+        }
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn parse_packed_refs_reads_traits_and_peeled_lines() {
+        let dir = temp_dir("parse-packed-refs");
+        let path = dir.join("packed-refs");
+        fs::write(
+            &path,
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             1111 refs/heads/main\n\
+             2222 refs/tags/v1\n\
+             ^3333\n",
+        )
+        .unwrap();
+
+        let packed = parse_packed_refs(&path).unwrap();
+        assert_eq!(packed.traits, ["peeled", "fully-peeled", "sorted"]);
+        assert_eq!(packed.refs["refs/heads/main"], ("1111".to_string(), None));
+        assert_eq!(packed.refs["refs/tags/v1"], ("2222".to_string(), Some("3333".to_string())));
+
+        fs::write(&path, "^3333\n").unwrap();
+        assert!(parse_packed_refs(&path).is_err());
+        fs::write(&path, "garbage\n").unwrap();
+        assert!(parse_packed_refs(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_packed_refs_resolves_by_ancestry() {
+        let dir = temp_dir("merge-packed-refs");
+        git_output(&dir, &["init", "--quiet"]).unwrap();
+        let base = commit(&dir, None, "base");
+        let ahead = commit(&dir, Some(&base), "ahead");
+        let other = commit(&dir, Some(&base), "other");
+        let config = test_config(&dir);
+        let incoming = dir.join("incoming");
+        let existing = dir.join("existing");
+
+        // Fast-forward: the incoming value wins
+        write_packed_refs(&incoming, "peeled sorted", &[(&ahead, "refs/heads/main")]);
+        write_packed_refs(&existing, "peeled sorted", &[(&base, "refs/heads/main")]);
+        let merged = merge_packed_refs(&config, &incoming, &existing, "there").unwrap();
+        assert_eq!(merged, format!("# pack-refs with: peeled sorted \n{} refs/heads/main\n", ahead));
+
+        // Behind: the existing value stays
+        write_packed_refs(&incoming, "peeled sorted", &[(&base, "refs/heads/main")]);
+        write_packed_refs(&existing, "peeled sorted", &[(&ahead, "refs/heads/main")]);
+        let merged = merge_packed_refs(&config, &incoming, &existing, "there").unwrap();
+        assert_eq!(merged, format!("# pack-refs with: peeled sorted \n{} refs/heads/main\n", ahead));
+
+        // Diverged: the existing value stays and the incoming one is kept aside
+        write_packed_refs(&incoming, "peeled sorted", &[(&other, "refs/heads/main")]);
+        let merged = merge_packed_refs(&config, &incoming, &existing, "there").unwrap();
+        assert_eq!(
+            merged,
+            format!(
+                "# pack-refs with: peeled sorted \n{} refs/heads/main\n{} refs/local-sync/there/main\n",
+                ahead, other
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_packed_refs_takes_union_and_keeps_peeled_lines() {
+        let dir = temp_dir("merge-packed-refs-union");
+        git_output(&dir, &["init", "--quiet"]).unwrap();
+        let base = commit(&dir, None, "base");
+        let config = test_config(&dir);
+        let incoming = dir.join("incoming");
+        let existing = dir.join("existing");
+
+        fs::write(
+            &incoming,
+            format!("# pack-refs with: peeled fully-peeled sorted \n{0} refs/tags/v1\n^{0}\n", base),
+        )
+        .unwrap();
+        write_packed_refs(&existing, "peeled sorted", &[(&base, "refs/heads/main")]);
+        let merged = merge_packed_refs(&config, &incoming, &existing, "there").unwrap();
+        assert_eq!(
+            merged,
+            format!(
+                "# pack-refs with: peeled sorted \n{0} refs/heads/main\n{0} refs/tags/v1\n^{0}\n",
+                base
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expand_path_expands_home_and_variables() {
+        // Names unique to this test, since tests share the environment
+        unsafe {
+            std::env::set_var("LOCAL_SYNC_TEST_NAS", "/mnt/nas");
+            std::env::set_var("LOCAL_SYNC_TEST_EMPTY", "");
+        }
+        let home = home_dir().unwrap();
+
+        assert_eq!(expand_path("~").unwrap(), home);
+        assert_eq!(expand_path("~/nas/x").unwrap(), PathBuf::from(format!("{}/nas/x", home.display())));
+        assert_eq!(expand_path("/a/~b").unwrap(), PathBuf::from("/a/~b"));
+        assert_eq!(expand_path("~user/x").unwrap(), PathBuf::from("~user/x"));
+        assert_eq!(expand_path("$LOCAL_SYNC_TEST_NAS/p").unwrap(), PathBuf::from("/mnt/nas/p"));
+        assert_eq!(expand_path("${LOCAL_SYNC_TEST_NAS}x").unwrap(), PathBuf::from("/mnt/nasx"));
+        assert_eq!(expand_path("${LOCAL_SYNC_TEST_NAS:-/other}").unwrap(), PathBuf::from("/mnt/nas"));
+        assert_eq!(expand_path("${LOCAL_SYNC_TEST_UNSET:-/srv/nas}/p").unwrap(), PathBuf::from("/srv/nas/p"));
+        assert_eq!(expand_path("${LOCAL_SYNC_TEST_EMPTY:-/srv}").unwrap(), PathBuf::from("/srv"));
+        assert_eq!(expand_path("${LOCAL_SYNC_TEST_UNSET:-}x").unwrap(), PathBuf::from("x"));
+        assert_eq!(expand_path("/a$/b").unwrap(), PathBuf::from("/a$/b"));
+    }
+
+    #[test]
+    fn expand_path_rejects_unset_and_unterminated() {
+        assert!(expand_path("$LOCAL_SYNC_TEST_UNSET/p").is_err());
+        assert!(expand_path("${LOCAL_SYNC_TEST_UNSET}").is_err());
+        assert!(expand_path("${LOCAL_SYNC_TEST_NAS").is_err());
+    }
+}